@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use crate::cryptography::encryption::EncryptionKey;
+use crate::cryptography::permissions;
+use crate::cryptography::secret::{SecretBytes, SecretString};
+
+// An ssh-agent style background process that keeps derived keys in memory
+// for a limited time, so commands can run without prompting and re-running
+// the KDF. Each connection carries a single request line and gets back zero
+// or more data lines followed by `OK` or `ERR <message>`:
+//
+//   PUT <name> <ttl secs> <kdf> <salt hex> <key hex>
+//   GET <name>                -> KEY <kdf> <salt hex> <key hex>
+//   LOCK [<name>]
+//   STATUS                    -> <name> <secs left, 0 = no limit> per key
+//   STOP
+
+// Longest request or response.
+const MAX_MESSAGE: usize = 4096;
+
+struct CachedKey {
+    key: EncryptionKey,
+    expires: Option<Instant>,
+}
+
+type Cache = Arc<Mutex<HashMap<String, CachedKey>>>;
+
+/// Directory holding the agent socket: `$XDG_RUNTIME_DIR/cli_wrapper`, or a
+/// per-user directory in /tmp.
+fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("cli_wrapper"),
+        None => std::env::temp_dir().join(format!("cli_wrapper-{}", permissions::current_uid())),
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    socket_dir().join("agent.sock")
+}
+
+/// Refuses a socket directory that another user could have planted or can
+/// get into, including a symlink to one.
+fn check_socket_dir(dir: &Path) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != permissions::current_uid() || metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!("Insecure agent socket directory {}", dir.display()));
+    }
+    Ok(())
+}
+
+/// Creates the socket directory, see `check_socket_dir`.
+fn prepare_socket_dir() -> anyhow::Result<PathBuf> {
+    let dir = socket_dir();
+    permissions::create_private_dir(&dir)?;
+    check_socket_dir(&dir)?;
+    Ok(dir)
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    // SAFETY: ucred is plain old data.
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes of the given size.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn read_message(stream: &mut UnixStream) -> io::Result<SecretBytes> {
+    SecretBytes::read_bounded(stream, MAX_MESSAGE)
+}
+
+fn valid_key_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_whitespace)
+}
+
+fn remove_expired(cache: &Cache) {
+    let now = Instant::now();
+    if let Ok(mut cache) = cache.lock() {
+        cache.retain(|_, cached| cached.expires.is_none_or(|expires| expires > now));
+    }
+}
+
+/// Handles one request, appending the response to `response`. Returns
+/// whether the agent should stop afterwards.
+fn handle_request(request: &str, cache: &Cache, response: &mut String) -> bool {
+    remove_expired(cache);
+    let fields: Vec<&str> = request.split_whitespace().collect();
+    let mut cache = match cache.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match fields.as_slice() {
+        ["PUT", name, ttl, key @ ..] if valid_key_name(name) => {
+            let (Ok(ttl), Some(key)) = (ttl.parse::<u64>(), EncryptionKey::from_fields(key)) else {
+                response.push_str("ERR malformed key\n");
+                return false;
+            };
+            let expires = (ttl > 0).then(|| Instant::now() + Duration::from_secs(ttl));
+            cache.insert(name.to_string(), CachedKey { key, expires });
+        }
+        ["GET", name] => match cache.get(*name) {
+            Some(cached) => {
+                response.push_str("KEY ");
+                cached.key.push_fields(response);
+                response.push('\n');
+            }
+            None => {
+                response.push_str("ERR not cached\n");
+                return false;
+            }
+        },
+        ["LOCK"] => cache.clear(),
+        ["LOCK", name] => {
+            cache.remove(*name);
+        }
+        ["STATUS"] => {
+            let now = Instant::now();
+            let mut names: Vec<&String> = cache.keys().collect();
+            names.sort();
+            for name in names {
+                let left = cache[name].expires.map_or(0, |expires| expires.duration_since(now).as_secs().max(1));
+                response.push_str(&format!("{} {}\n", name, left));
+            }
+        }
+        ["STOP"] => {
+            cache.clear();
+            response.push_str("OK\n");
+            return true;
+        }
+        _ => {
+            response.push_str("ERR unknown request\n");
+            return false;
+        }
+    }
+    response.push_str("OK\n");
+    false
+}
+
+/// Runs the agent in the current process until it receives `STOP`.
+pub fn serve() -> anyhow::Result<()> {
+    prepare_socket_dir()?;
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err(anyhow!("An agent is already listening on {}", path.display()));
+    }
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(permissions::FILE_MODE))?;
+    println!("Agent listening on {}", path.display());
+
+    let cache: Cache = Arc::new(Mutex::new(HashMap::new()));
+    let expiry_cache = Arc::clone(&cache);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        remove_expired(&expiry_cache);
+    });
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        if peer_uid(&stream).ok() != Some(permissions::current_uid()) {
+            continue;
+        }
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let request = match read_message(&mut stream) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        let mut stop = false;
+        let response = SecretString::build(MAX_MESSAGE, |response| match std::str::from_utf8(request.expose()) {
+            Ok(request) => stop = handle_request(request, &cache, response),
+            Err(_) => response.push_str("ERR invalid request\n"),
+        });
+        let _ = stream.write_all(response.expose().as_bytes());
+        if stop {
+            break;
+        }
+    }
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+/// Starts the agent as a detached background process and waits until it
+/// accepts connections.
+pub fn spawn() -> anyhow::Result<()> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err(anyhow!("An agent is already listening on {}", path.display()));
+    }
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(["agent", "start", "--foreground"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async-signal-safe, as required between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+
+    for _ in 0..50 {
+        if UnixStream::connect(&path).is_ok() {
+            println!("Agent started (pid {}), listening on {}", child.id(), path.display());
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err(anyhow!("Agent did not start listening on {}", path.display()))
+}
+
+/// Sends one request to the running agent and returns its data lines.
+/// Sends `line` to the agent. Nothing is sent unless the socket is in a
+/// directory only we can enter and the process serving it runs as us, so
+/// another user cannot pose as the agent to collect keys.
+fn request(line: &str) -> anyhow::Result<Vec<SecretString>> {
+    let dir = socket_dir();
+    if fs::symlink_metadata(&dir).is_err() {
+        return Err(anyhow!("No agent running"));
+    }
+    check_socket_dir(&dir)?;
+    let mut stream = UnixStream::connect(socket_path()).map_err(|e| anyhow!("No agent running: {}", e))?;
+    let uid = peer_uid(&stream)?;
+    if uid != permissions::current_uid() {
+        return Err(anyhow!("Agent socket is served by uid {}, not by you", uid));
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(line.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let response = read_message(&mut stream)?;
+    let response = std::str::from_utf8(response.expose()).map_err(|_| anyhow!("Invalid response from agent"))?;
+
+    let mut lines = Vec::new();
+    for line in response.lines() {
+        if line == "OK" {
+            return Ok(lines);
+        }
+        if let Some(message) = line.strip_prefix("ERR ") {
+            return Err(anyhow!("Agent: {}", message));
+        }
+        lines.push(SecretString::new(line.to_string()));
+    }
+    Err(anyhow!("Incomplete response from agent"))
+}
+
+/// The key the agent holds for `key_name`, if an agent is running and has it.
+pub fn cached_key(key_name: &str) -> Option<EncryptionKey> {
+    if !valid_key_name(key_name) {
+        return None;
+    }
+    let lines = request(&format!("GET {}", key_name)).ok()?;
+    let line = lines.first()?;
+    let fields: Vec<&str> = line.expose().strip_prefix("KEY ")?.split(' ').collect();
+    EncryptionKey::from_fields(&fields)
+}
+
+pub fn add_key(key_name: &str, key: &EncryptionKey, ttl: u64) -> anyhow::Result<()> {
+    if !valid_key_name(key_name) {
+        return Err(anyhow!("Key names containing whitespace cannot be cached"));
+    }
+    let line = SecretString::build(MAX_MESSAGE, |line| {
+        line.push_str(&format!("PUT {} {} ", key_name, ttl));
+        key.push_fields(line);
+    });
+    request(line.expose())?;
+    Ok(())
+}
+
+pub fn lock(key_name: Option<&str>) -> anyhow::Result<()> {
+    match key_name {
+        Some(key_name) => request(&format!("LOCK {}", key_name))?,
+        None => request("LOCK")?,
+    };
+    Ok(())
+}
+
+/// Names of the cached keys with the seconds until each expires (0 = never).
+pub fn status() -> anyhow::Result<Vec<(String, u64)>> {
+    let mut keys = Vec::new();
+    for line in request("STATUS")? {
+        if let Some((name, left)) = line.expose().rsplit_once(' ') {
+            keys.push((name.to_string(), left.parse().unwrap_or(0)));
+        }
+    }
+    Ok(keys)
+}
+
+pub fn stop() -> anyhow::Result<()> {
+    request("STOP")?;
+    Ok(())
+}
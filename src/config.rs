@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use dirs::home_dir;
+use anyhow::anyhow;
+use crate::cryptography::atomic::write_atomic;
+use crate::cryptography::derive::Kdf;
+use crate::cryptography::permissions;
+
+const CONFIG_FILE: &str = "cli_wrapper.toml";
+
+/// Settings read from `~/.commune/cli_wrapper.toml`. Every field has a
+/// default, so a missing file or missing keys are fine.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub kdf: KdfConfig,
+    pub password: PasswordConfig,
+    pub permissions: PermissionsConfig,
+    pub staging: StagingConfig,
+    pub hardening: HardeningConfig,
+    pub pinentry: PinentryConfig,
+    pub agent: AgentConfig,
+    pub keyring: KeyringConfig,
+    pub session: SessionConfig,
+    pub history: HistoryConfig,
+}
+
+/// Argon2id cost parameters used when a key is encrypted for the first time.
+/// `target_ms` is the unlock time `--calibrate` aims for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KdfConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub target_ms: u64,
+}
+
+impl Default for KdfConfig {
+    fn default() -> Self {
+        KdfConfig {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+            target_ms: 1000,
+        }
+    }
+}
+
+impl KdfConfig {
+    pub fn to_kdf(&self) -> Kdf {
+        Kdf::Argon2id {
+            memory_kib: self.memory_kib,
+            iterations: self.iterations,
+            parallelism: self.parallelism,
+        }
+    }
+
+    pub fn set_kdf(&mut self, kdf: &Kdf) {
+        if let Kdf::Argon2id { memory_kib, iterations, parallelism } = *kdf {
+            self.memory_kib = memory_kib;
+            self.iterations = iterations;
+            self.parallelism = parallelism;
+        }
+    }
+}
+
+/// Whether a failed check aborts or only prints a warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Refuse,
+    Warn,
+}
+
+/// Strength requirements for passwords chosen when a key is first encrypted
+/// or the vault password is changed, and how many times an existing
+/// password may be entered before giving up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    /// Applied when a new password scores below `min_score` (0-4).
+    pub min_score: u8,
+    pub policy: Policy,
+    pub attempts: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            min_score: 3,
+            policy: Policy::Refuse,
+            attempts: 3,
+        }
+    }
+}
+
+/// What to do when the key directory or its files are accessible to other
+/// users or owned by someone else.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionsConfig {
+    pub policy: Policy,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        PermissionsConfig { policy: Policy::Warn }
+    }
+}
+
+/// Where a key is decrypted to while `comx` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StagingMode {
+    /// `~/.commune/key/<name>.json` on the real filesystem.
+    Disk,
+    /// A private directory on tmpfs, handed to `comx` as its `HOME`.
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StagingConfig {
+    pub mode: StagingMode,
+}
+
+impl Default for StagingConfig {
+    fn default() -> Self {
+        StagingConfig { mode: StagingMode::Disk }
+    }
+}
+
+/// Disable core dumps and ptrace access and lock secrets into RAM at startup.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HardeningConfig {
+    pub enabled: bool,
+}
+
+/// Ask for passwords and mnemonics in a pinentry dialog instead of on the
+/// terminal. `program` is looked up in PATH unless it is a path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinentryConfig {
+    pub enabled: bool,
+    pub program: String,
+}
+
+impl Default for PinentryConfig {
+    fn default() -> Self {
+        PinentryConfig {
+            enabled: false,
+            program: "pinentry".to_string(),
+        }
+    }
+}
+
+/// How long `agent unlock` leaves a key in the agent by default, in seconds.
+/// 0 keeps it until the agent is locked or stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub ttl_secs: u64,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig { ttl_secs: 15 * 60 }
+    }
+}
+
+/// Cache derived keys in the kernel session keyring for `timeout_secs`
+/// seconds, so later runs in the same login session skip the password.
+/// 0 keeps them until forgotten or the session ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyringConfig {
+    pub enabled: bool,
+    pub timeout_secs: u64,
+}
+
+impl Default for KeyringConfig {
+    fn default() -> Self {
+        KeyringConfig {
+            enabled: false,
+            timeout_secs: 15 * 60,
+        }
+    }
+}
+
+/// Limits for the interactive shell, in seconds: the key is locked after
+/// `idle_timeout_secs` without input, and `max_lifetime_secs` after it was
+/// unlocked. 0 disables a limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub idle_timeout_secs: u64,
+    pub max_lifetime_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout_secs: 15 * 60,
+            max_lifetime_secs: 8 * 60 * 60,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        (self.max_lifetime_secs > 0).then(|| Duration::from_secs(self.max_lifetime_secs))
+    }
+}
+
+/// How the interactive shell keeps its history in `~/.commune/history`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryMode {
+    /// Not saved between sessions.
+    Off,
+    /// One plain text file per key.
+    Plain,
+    /// One file per key, encrypted with the key's own password.
+    Encrypted,
+}
+
+/// Shell history settings. `max_entries` is the number of lines kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub mode: HistoryMode,
+    pub max_entries: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            mode: HistoryMode::Encrypted,
+            max_entries: 1000,
+        }
+    }
+}
+
+pub fn config_path() -> anyhow::Result<PathBuf> {
+    let home = home_dir().ok_or_else(|| anyhow!("Home directory not found"))?;
+    Ok(home.join(".commune").join(CONFIG_FILE))
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Config> {
+        let path = config_path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        permissions::check_config(&path)?;
+        let contents = fs::read_to_string(&path)?;
+        toml::from_str(&contents).map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Writes `kdf` into the `[kdf]` section of the config file, leaving the
+    /// rest of the file, including comments and formatting, as it is. The
+    /// in-memory `Config` is not saved as a whole because command line flags
+    /// may have changed it for this run.
+    pub fn save_kdf(kdf: &KdfConfig) -> anyhow::Result<()> {
+        let path = config_path()?;
+        let mut document = if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            contents
+                .parse::<toml_edit::DocumentMut>()
+                .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?
+        } else {
+            toml_edit::DocumentMut::new()
+        };
+        if !document.get("kdf").is_some_and(|item| item.is_table_like()) {
+            document.insert("kdf", toml_edit::table());
+        }
+        let section = &mut document["kdf"];
+        set_keeping_comment(section, "memory_kib", i64::from(kdf.memory_kib));
+        set_keeping_comment(section, "iterations", i64::from(kdf.iterations));
+        set_keeping_comment(section, "parallelism", i64::from(kdf.parallelism));
+        set_keeping_comment(section, "target_ms", kdf.target_ms as i64);
+        if let Some(parent) = path.parent() {
+            permissions::create_private_dir(parent)?;
+        }
+        write_atomic(&path, document.to_string().as_bytes())?;
+        Ok(())
+    }
+}
+
+// Replaces the value of `key` but keeps the whitespace and trailing comment
+// around the old one.
+fn set_keeping_comment(section: &mut toml_edit::Item, key: &str, value: i64) {
+    let mut new_value = toml_edit::Value::from(value);
+    if let Some(old_value) = section.get(key).and_then(toml_edit::Item::as_value) {
+        *new_value.decor_mut() = old_value.decor().clone();
+    }
+    section[key] = toml_edit::Item::Value(new_value);
+}
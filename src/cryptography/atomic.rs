@@ -0,0 +1,109 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use rand::RngCore;
+use crate::cryptography::cleanup::secure_remove;
+use crate::cryptography::permissions::create_private_file;
+
+// Crash-safe replacement of key files: the new contents are written to a
+// temporary file in the same directory, fsynced, renamed over the target and
+// the directory is fsynced so the rename itself is durable. A crash at any
+// point leaves either the old file or the new one, never a truncated mix.
+
+/// A temporary file that replaces `target` when committed. Dropping it
+/// without committing removes the temporary file and leaves `target` as is.
+pub struct AtomicFile {
+    target: PathBuf,
+    temp_path: PathBuf,
+    file: Option<File>,
+}
+
+/// `bytes` random bytes in hex, for file and directory names that cannot be
+/// guessed in advance.
+pub fn random_hex(bytes: usize) -> String {
+    let mut random = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut random);
+    random.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn temp_path_for(target: &Path) -> io::Result<PathBuf> {
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".tmp-{}", random_hex(6)));
+    Ok(target.with_file_name(temp_name))
+}
+
+/// Fsyncs a directory so that renames and unlinks inside it are durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl AtomicFile {
+    pub fn create(target: &Path) -> io::Result<AtomicFile> {
+        let temp_path = temp_path_for(target)?;
+        let file = create_private_file(&temp_path)?;
+        Ok(AtomicFile {
+            target: target.to_path_buf(),
+            temp_path,
+            file: Some(file),
+        })
+    }
+
+    /// Path of the not yet committed contents, e.g. to read them back.
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("file is only taken by commit")
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file().write_all(data)
+    }
+
+    /// Flushes the contents to disk without replacing the target yet.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file().sync_all()
+    }
+
+    /// Fsyncs the contents, renames them over the target and fsyncs the
+    /// containing directory.
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = self.file.take().expect("file is only taken by commit");
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp_path, &self.target)?;
+        if let Some(parent) = self.target.parent() {
+            sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // The uncommitted contents may be plaintext key material.
+        if self.file.take().is_some() {
+            let _ = secure_remove(&self.temp_path);
+        }
+    }
+}
+
+/// Replaces `path` with whatever `write` puts into the temporary file. If
+/// `write` fails the target is left untouched.
+pub fn write_atomic_with<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let mut atomic = AtomicFile::create(path)?;
+    write(atomic.file())?;
+    atomic.commit()
+}
+
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |file| file.write_all(data))
+}
@@ -1,20 +1,315 @@
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::process::{Child, Output};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use crate::cryptography::atomic::{random_hex, sync_dir};
+use crate::cryptography::permissions;
+use crate::cryptography::secret::SecretBytes;
+use crate::cryptography::staging;
+use crate::cryptography::encryption::{self, EncryptionError, EncryptionKey, KeyFileState};
 
-pub fn cleanup_decrypted_key(key_name: &str) -> std::io::Result<()> {
-    let key_path = format!("/.commune/key/{}.json", key_name);
-    if fs::metadata(&key_path).is_ok() {
-        fs::remove_file(&key_path)?;
+// Keys whose plaintext is currently staged by a `KeyFileGuard`, with a
+// digest of the plaintext as last encrypted. The signal handler and panic
+// hook clean these up, since neither runs destructors.
+static STAGED_KEYS: Mutex<Vec<(String, [u8; 32])>> = Mutex::new(Vec::new());
+
+// The `comx` process currently running, if any. The signal handler stops it
+// first, so it neither writes to a key that is being removed nor outlives
+// the wrapper.
+static RUNNING_CHILD: Mutex<Option<i32>> = Mutex::new(None);
+
+// How long `comx` gets to exit after SIGTERM before it is killed.
+const CHILD_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Temporary files from an interrupted atomic write may hold plaintext key
+// material. Younger ones may belong to a wrapper that is still running.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+
+/// Overwrites a file with random data, fsyncs, truncates it, renames it to a
+/// random name and unlinks it, so neither its contents nor its name linger in
+/// the directory or in the blocks it occupied.
+///
+/// Symlinks and files with more than one hard link are only unlinked: their
+/// data is also reachable from outside the vault, e.g. as a backup copy, and
+/// must not be destroyed.
+///
+/// This only helps on filesystems that rewrite blocks in place (e.g. ext4
+/// without data journalling). Copy-on-write filesystems (btrfs, ZFS, APFS),
+/// snapshots, backups and SSD wear levelling can all keep the old blocks, so
+/// treat it as damage limitation; keeping plaintext off disk is the real fix.
+pub fn secure_remove(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_file() && metadata.nlink() == 1 {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        // The path may have been swapped between the two checks.
+        let opened = file.metadata()?;
+        if opened.dev() == metadata.dev() && opened.ino() == metadata.ino() && opened.nlink() == 1 {
+            overwrite(file, opened.len())?;
+        }
+    }
+
+    let renamed = path.with_file_name(format!(".{}", random_hex(12)));
+    fs::rename(path, &renamed)?;
+    fs::remove_file(&renamed)?;
+    if let Some(parent) = path.parent() {
+        sync_dir(parent)?;
     }
     Ok(())
 }
 
+fn overwrite(mut file: File, len: u64) -> io::Result<()> {
+    let mut buffer = [0u8; 4096];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(buffer.len() as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut buffer[..chunk]);
+        file.write_all(&buffer[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()?;
+    file.set_len(0)?;
+    file.sync_all()
+}
+
+pub fn cleanup_decrypted_key(key_name: &str) -> Result<(), EncryptionError> {
+    let key_path = encryption::get_key_path(key_name)?;
+    if fs::symlink_metadata(&key_path).is_ok() {
+        secure_remove(&key_path)?;
+    }
+    let marker_path = encryption::get_staged_marker_path(key_name)?;
+    if marker_path.exists() {
+        fs::remove_file(&marker_path)?;
+    }
+    Ok(())
+}
+
+fn plaintext_digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn set_staged_digest(key_name: &str, digest: [u8; 32]) {
+    if let Ok(mut staged) = STAGED_KEYS.lock() {
+        staged.retain(|(name, _)| name != key_name);
+        staged.push((key_name.to_string(), digest));
+    }
+}
+
+/// Waits for `child` to exit and collects its output. While it runs, the
+/// signal handler and panic hook know to stop it.
+pub fn wait_for_child(child: Child) -> io::Result<Output> {
+    if let Ok(mut running) = RUNNING_CHILD.lock() {
+        *running = Some(child.id() as i32);
+    }
+    let output = child.wait_with_output();
+    if let Ok(mut running) = RUNNING_CHILD.lock() {
+        *running = None;
+    }
+    output
+}
+
+// Sends SIGTERM to the running `comx`, then SIGKILL if it has not exited
+// within CHILD_EXIT_TIMEOUT.
+fn stop_running_child() {
+    let pid = match RUNNING_CHILD.lock() {
+        Ok(running) => *running,
+        Err(poisoned) => *poisoned.into_inner(),
+    };
+    let Some(pid) = pid else { return };
+    // SAFETY: kill and waitpid only take plain integers. The pid is our own
+    // child; only in the moment between `wait_with_output` reaping it and
+    // RUNNING_CHILD being cleared could it have been reused.
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+        let deadline = Instant::now() + CHILD_EXIT_TIMEOUT;
+        while libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) == 0 {
+            if Instant::now() >= deadline {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+fn cleanup_staged_keys() {
+    stop_running_child();
+    let staged = match STAGED_KEYS.lock() {
+        Ok(staged) => staged.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    for (key_name, digest) in staged {
+        let unchanged = match encryption::get_key_path(&key_name).map(fs::read) {
+            Ok(Ok(data)) => plaintext_digest(SecretBytes::new(data).expose()) == digest,
+            _ => true,
+        };
+        if unchanged {
+            let _ = cleanup_decrypted_key(&key_name);
+        } else {
+            // Removing it would lose the change; `recover_stale_key`
+            // re-encrypts it on the next start.
+            eprintln!("Key '{}' was changed by comx; its plaintext is kept until the next run", key_name);
+        }
+    }
+    staging::remove_registered_dirs();
+}
+
+/// Stops `comx` and removes staged plaintext keys on SIGINT, SIGTERM, SIGHUP
+/// and panics. A plaintext key `comx` changed since it was last encrypted is
+/// kept with its staged marker, so the change is not lost.
+pub fn install_handlers() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        cleanup_staged_keys();
+        std::process::exit(130);
+    })?;
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        cleanup_staged_keys();
+        default_hook(info);
+    }));
+    Ok(())
+}
+
+/// Keeps a key decrypted for the duration of a command and removes the
+/// plaintext again when dropped, unless it was already there before, e.g.
+/// after `unlock`.
 pub struct KeyFileGuard {
     pub key_name: String,
+    wrote_plaintext: bool,
+}
+
+impl KeyFileGuard {
+    /// Decrypts `key_name` and marks the plaintext as staged, so a crash
+    /// before the guard is dropped is detected on the next start. A
+    /// plaintext that is already there is used as it is and left alone.
+    ///
+    /// The marker is created before decrypting so a crash in between is
+    /// caught too. If decrypting fails, only a marker created here is
+    /// removed: any plaintext on disk was not written by this call.
+    pub fn stage(key_name: &str, encryption_key: &EncryptionKey) -> Result<KeyFileGuard, EncryptionError> {
+        let wrote_plaintext =
+            encryption::key_file_state(key_name, encryption_key)? == KeyFileState::OnlyEncrypted;
+        let marker_path = encryption::get_staged_marker_path(key_name)?;
+        let created_marker = wrote_plaintext && !marker_path.exists();
+        if created_marker {
+            permissions::create_private_file(&marker_path)?;
+        }
+        if let Err(e) = encryption::decrypt_key_file(key_name, encryption_key) {
+            if created_marker {
+                let _ = fs::remove_file(&marker_path);
+            }
+            return Err(e);
+        }
+        if wrote_plaintext {
+            let data = SecretBytes::new(fs::read(encryption::get_key_path(key_name)?)?);
+            set_staged_digest(key_name, plaintext_digest(data.expose()));
+        }
+        Ok(KeyFileGuard {
+            key_name: key_name.to_string(),
+            wrote_plaintext,
+        })
+    }
+
+    /// Re-encrypts the staged plaintext if `comx` changed it. Returns whether
+    /// the encrypted file was rewritten.
+    pub fn sync(&self, encryption_key: &EncryptionKey) -> Result<bool, EncryptionError> {
+        match encryption::key_file_state(&self.key_name, encryption_key)? {
+            KeyFileState::Diverged | KeyFileState::OnlyPlaintext => {
+                let data = SecretBytes::new(fs::read(encryption::get_key_path(&self.key_name)?)?);
+                encryption::write_encrypted_key(&self.key_name, data.expose(), encryption_key)?;
+                if self.wrote_plaintext {
+                    set_staged_digest(&self.key_name, plaintext_digest(data.expose()));
+                }
+                Ok(true)
+            }
+            KeyFileState::Matching | KeyFileState::OnlyEncrypted | KeyFileState::Missing => Ok(false),
+        }
+    }
 }
 
 impl Drop for KeyFileGuard {
     fn drop(&mut self) {
+        if !self.wrote_plaintext {
+            return;
+        }
         let _ = cleanup_decrypted_key(&self.key_name);
+        if let Ok(mut staged) = STAGED_KEYS.lock() {
+            staged.retain(|(name, _)| name != &self.key_name);
+        }
+    }
+}
+
+/// Keys whose plaintext was staged by a previous run that never cleaned up,
+/// e.g. because it was killed.
+pub fn stale_keys() -> Result<Vec<String>, EncryptionError> {
+    let mut stale = Vec::new();
+    for key_name in encryption::list_staged_keys()? {
+        if encryption::get_key_path(&key_name)?.exists() {
+            stale.push(key_name);
+        }
+    }
+    Ok(stale)
+}
+
+/// Deals with plaintext left behind by a crashed run. A copy identical to the
+/// encrypted file is removed; one that `comx` changed before the crash is
+/// re-encrypted, as the interrupted command would have done.
+pub fn recover_stale_key(key_name: &str, encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    let marker_path = encryption::get_staged_marker_path(key_name)?;
+    if !marker_path.exists() {
+        return Ok(());
+    }
+    match encryption::key_file_state(key_name, encryption_key)? {
+        KeyFileState::Matching => {
+            println!("Removing plaintext key left by a previous run: {}", key_name);
+            cleanup_decrypted_key(key_name)?;
+        }
+        KeyFileState::Diverged | KeyFileState::OnlyPlaintext => {
+            println!("Re-encrypting plaintext key left by a previous run: {}", key_name);
+            encryption::encrypt_key_file(key_name, encryption_key)?;
+            cleanup_decrypted_key(key_name)?;
+        }
+        KeyFileState::OnlyEncrypted | KeyFileState::Missing => {
+            fs::remove_file(&marker_path)?;
+        }
     }
+    Ok(())
 }
 
+/// Removes temporary files left by interrupted atomic writes in the key
+/// directories.
+pub fn remove_stale_temp_files() -> Result<(), EncryptionError> {
+    let key_directory = encryption::get_key_directory()?;
+    for dir in [key_directory.clone(), key_directory.join("encrypted")] {
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !(name.starts_with('.') && name.contains(".tmp-")) {
+                continue;
+            }
+            let age = entry
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .unwrap_or(Duration::ZERO);
+            if age >= STALE_TEMP_AGE {
+                println!("Removing leftover temporary file: {}", entry.path().display());
+                secure_remove(&entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
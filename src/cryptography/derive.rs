@@ -1,5 +1,9 @@
+use argon2::{Algorithm, Argon2, Params, Version};
 use ring::pbkdf2;
+use sha2::{Digest, Sha256};
 use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+use crate::cryptography::secret::SecretKey;
 
 // /// Derive a key from a mnemonic phrase.
 // pub fn derive_key_from_mnemonic(mnemonic: &str) -> [u8; 32] {
@@ -11,16 +15,108 @@ use std::num::NonZeroU32;
 //     key
 // }
 
-pub fn derive_key_from_password(password: &str, salt: &[u8]) -> [u8; 32] {
-    let mut key = [0u8; 32];
-    pbkdf2::derive(
-        pbkdf2::PBKDF2_HMAC_SHA256,
-        NonZeroU32::new(100_000).unwrap(),
-        salt,
-        password.as_bytes(),
-        &mut key,
-    );
-    key
+pub const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// The KDF used by key files written before the versioned header existed.
+/// PBKDF2 is only kept for reading those; new keys use Argon2id.
+pub const LEGACY_KDF: Kdf = Kdf::Pbkdf2Sha256 { iterations: PBKDF2_ITERATIONS };
+
+/// A key derivation function together with the cost parameters it runs with.
+/// These are recorded in the header of every encrypted key file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    Pbkdf2Sha256 { iterations: u32 },
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+
+fn argon2_params(memory_kib: u32, iterations: u32, parallelism: u32) -> anyhow::Result<Params> {
+    Params::new(memory_kib, iterations, parallelism, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2id parameters: {}", e))
+}
+
+impl Kdf {
+    /// Checks the cost parameters without running the KDF.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match *self {
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                if iterations == 0 {
+                    return Err(anyhow::anyhow!("Invalid PBKDF2 iteration count"));
+                }
+            }
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                argon2_params(memory_kib, iterations, parallelism)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn derive_key_from_password(password: &str, salt: &[u8], kdf: &Kdf) -> anyhow::Result<SecretKey> {
+    let mut key = SecretKey::zeroed();
+    match kdf {
+        Kdf::Pbkdf2Sha256 { iterations } => {
+            let iterations = NonZeroU32::new(*iterations)
+                .ok_or_else(|| anyhow::anyhow!("Invalid PBKDF2 iteration count"))?;
+            pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                iterations,
+                salt,
+                password.as_bytes(),
+                key.expose_mut(),
+            );
+        }
+        Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+            let params = argon2_params(*memory_kib, *iterations, *parallelism)?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password.as_bytes(), salt, key.expose_mut())
+                .map_err(|e| anyhow::anyhow!("Argon2id key derivation failed: {}", e))?;
+        }
+    }
+    Ok(key)
+}
+
+/// Fingerprint of a derived key, stored in the key file header so a wrong
+/// password can be told apart from a corrupted file before decrypting.
+pub fn key_check_value(key: &[u8; 32]) -> [u8; 16] {
+    let digest = Sha256::new()
+        .chain_update(b"cli_wrapper key check")
+        .chain_update(key)
+        .finalize();
+    let mut check = [0u8; 16];
+    check.copy_from_slice(&digest[..16]);
+    check
+}
+
+/// Smallest Argon2id memory cost calibration will fall back to (19 MiB, the
+/// OWASP recommended minimum).
+pub const MIN_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+
+fn time_derivation(kdf: &Kdf) -> anyhow::Result<Duration> {
+    let salt = generate_salt();
+    let start = Instant::now();
+    derive_key_from_password("calibration", &salt, kdf)?;
+    Ok(start.elapsed())
+}
+
+/// Picks Argon2id parameters whose derivation takes roughly `target` on this
+/// machine. Memory starts at `memory_kib` and is only halved if a single pass
+/// is already slower than the target; the remaining budget goes to iterations.
+pub fn calibrate_argon2id(target: Duration, memory_kib: u32, parallelism: u32) -> anyhow::Result<(Kdf, Duration)> {
+    let mut memory_kib = memory_kib.max(MIN_ARGON2_MEMORY_KIB);
+    let single_pass = loop {
+        let kdf = Kdf::Argon2id { memory_kib, iterations: 1, parallelism };
+        let elapsed = time_derivation(&kdf)?;
+        if elapsed <= target || memory_kib == MIN_ARGON2_MEMORY_KIB {
+            break elapsed;
+        }
+        memory_kib = (memory_kib / 2).max(MIN_ARGON2_MEMORY_KIB);
+    };
+
+    let ratio = target.as_secs_f64() / single_pass.as_secs_f64().max(f64::EPSILON);
+    let iterations = (ratio.floor() as u32).max(1);
+    let kdf = Kdf::Argon2id { memory_kib, iterations, parallelism };
+    let measured = time_derivation(&kdf)?;
+    Ok((kdf, measured))
 }
 
 pub fn generate_salt() -> [u8; 16] {
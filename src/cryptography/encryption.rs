@@ -1,28 +1,48 @@
 use aes_gcm::aead::generic_array::GenericArray;
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
 use rand::Rng;
-use zeroize::Zeroize;
+use ring::constant_time::verify_slices_are_equal;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use dirs::home_dir;
 use anyhow::anyhow;
 use crate::cryptography::input;
+use crate::cryptography::atomic::{sync_dir, write_atomic, AtomicFile};
+use crate::cryptography::cleanup;
 use crate::cryptography::derive;
+use crate::cryptography::derive::Kdf;
+use crate::cryptography::format::{self, Cipher, Header, KeyFile};
+use crate::cryptography::permissions;
+use crate::cryptography::secret::{SecretBytes, SecretKey, SecretString};
+use crate::config::Config;
 
 
 const SALT_FILE: &str = ".commune_salt";
 // const PASSWORD_ENV: &str = "COMX_PASSWORD";
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum EncryptionError {
     IoError(std::io::Error),
     AesError(String), // Changed to store a String description
     RandError(rand::Error),
+    FormatError(String),
+    /// The key file has a key check value and the derived key does not match it.
+    WrongPassword,
+    /// The key file has no key check value, so decryption failing could mean
+    /// either a wrong password or corruption.
+    AuthenticationFailed,
+    /// The password was verified but the ciphertext does not authenticate.
+    CorruptedFile(String),
+    /// A freshly written key file did not decrypt back to the original.
+    VerificationFailed(String),
+    /// The plaintext and encrypted copies of the named key hold different keys.
+    Diverged(String),
 }
 
 impl fmt::Display for EncryptionError {
@@ -31,6 +51,16 @@ impl fmt::Display for EncryptionError {
             EncryptionError::IoError(e) => write!(f, "IO error: {}", e),
             EncryptionError::AesError(e) => write!(f, "AES error: {}", e),
             EncryptionError::RandError(e) => write!(f, "Random number generator error: {}", e),
+            EncryptionError::FormatError(e) => write!(f, "Key file format error: {}", e),
+            EncryptionError::WrongPassword => write!(f, "Wrong password"),
+            EncryptionError::AuthenticationFailed => write!(f, "Wrong password or corrupted key file"),
+            EncryptionError::CorruptedFile(e) => write!(f, "Key file is corrupted: {}", e),
+            EncryptionError::VerificationFailed(e) => write!(f, "Encrypted key file verification failed: {}", e),
+            EncryptionError::Diverged(name) => write!(
+                f,
+                "Plaintext and encrypted copies of key '{}' differ; run `cli-wrapper reconcile {} keep-plaintext` or `cli-wrapper reconcile {} keep-encrypted`",
+                name, name, name
+            ),
         }
     }
 }
@@ -41,10 +71,24 @@ impl Error for EncryptionError {
             EncryptionError::IoError(e) => Some(e),
             EncryptionError::AesError(_) => None, // AesError no longer has a source
             EncryptionError::RandError(e) => Some(e),
+            EncryptionError::FormatError(_) => None,
+            EncryptionError::WrongPassword => None,
+            EncryptionError::AuthenticationFailed => None,
+            EncryptionError::CorruptedFile(_) => None,
+            EncryptionError::VerificationFailed(_) => None,
+            EncryptionError::Diverged(_) => None,
         }
     }
 }
 
+impl EncryptionError {
+    /// Whether the error means the password was (probably) wrong, so an
+    /// interactive caller may ask for it again.
+    pub fn is_wrong_password(&self) -> bool {
+        matches!(self, EncryptionError::WrongPassword | EncryptionError::AuthenticationFailed)
+    }
+}
+
 impl From<std::io::Error> for EncryptionError {
     fn from(error: std::io::Error) -> Self {
         EncryptionError::IoError(error)
@@ -63,23 +107,43 @@ impl From<rand::Error> for EncryptionError {
     }
 }
 
-fn get_key_directory() -> Result<PathBuf, EncryptionError> {
+pub fn get_key_directory() -> Result<PathBuf, EncryptionError> {
     let home = home_dir().ok_or_else(|| EncryptionError::IoError(std::io::Error::new(
         std::io::ErrorKind::NotFound,
         "Home directory not found",
     )))?;
     let path = home.join(".commune").join("key");
-    println!("Key directory: {:?}", path.display());
 
     // Create the directory if it doesn't exist
     if !path.exists() {
-        fs::create_dir_all(&path).map_err(EncryptionError::IoError)?;
+        permissions::create_private_dir(&path).map_err(EncryptionError::IoError)?;
     }
 
     Ok(path)
 }
 
-fn get_key_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
+/// Checks that `key_name` can only name a file directly inside the key
+/// directory: it must not be empty, `.` or `..`, or contain `/` or NUL.
+pub fn check_key_name(key_name: &str) -> anyhow::Result<()> {
+    if key_name.is_empty() {
+        return Err(anyhow!("Key name is empty"));
+    }
+    if key_name == "." || key_name == ".." || key_name.contains(['/', '\0']) {
+        return Err(anyhow!("Invalid key name '{}': it must not be '.' or '..' or contain '/'", key_name));
+    }
+    Ok(())
+}
+
+/// Fails with a clear error, before any password is asked for, if there is
+/// neither an encrypted nor a plaintext file for `key_name`.
+pub fn check_key_exists(key_name: &str) -> anyhow::Result<()> {
+    if !get_encrypted_key_path(key_name)?.exists() && !get_key_path(key_name)?.exists() {
+        return Err(anyhow!("Key '{}' not found; `cli-wrapper list` shows the available keys", key_name));
+    }
+    Ok(())
+}
+
+pub fn get_key_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
     // println!("Debug: Getting key path for: {}", key_name);
     let mut path = get_key_directory()?;
     path.push(format!("{}.json", key_name));
@@ -87,6 +151,16 @@ fn get_key_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
     Ok(path)
 }
 
+/// Marker that exists while the wrapper has a key decrypted for a command.
+/// One that outlives the process means the plaintext was left behind.
+pub fn get_staged_marker_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
+    let mut path = get_key_directory()?;
+    path.push("encrypted");
+    permissions::create_private_dir(&path)?;
+    path.push(format!("{}.staged", key_name));
+    Ok(path)
+}
+
 fn get_encrypted_key_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
     // println!("Debug: Getting encrypted key path for: {}", key_name);
     let mut path = get_key_directory()?;
@@ -96,44 +170,298 @@ fn get_encrypted_key_path(key_name: &str) -> Result<PathBuf, EncryptionError> {
     Ok(path)
 }
 
-fn get_or_create_salt() -> Result<[u8; 16], anyhow::Error> {
+/// Reads the shared salt that headerless (version 0) key files were keyed
+/// with. New key files carry their own salt and never touch this file.
+fn get_legacy_salt() -> Result<[u8; 16], anyhow::Error> {
     let directory_path = get_key_directory()?;
     let salt_path = directory_path.join("encrypted").join(SALT_FILE);
 
-    // Ensure the 'encrypted' directory exists
-    if let Some(parent) = salt_path.parent() {
-        fs::create_dir_all(parent)?;
+    if !salt_path.exists() {
+        return Err(anyhow!(
+            "Shared salt file {} not found; it is required to decrypt key files in the old format",
+            salt_path.display()
+        ));
     }
+    let salt = fs::read(&salt_path)?;
+    salt.try_into().map_err(|_| anyhow!("Invalid salt length"))
+}
 
-    if salt_path.exists() {
-        // If the salt file exists, read it
-        let salt = fs::read(&salt_path)?;
-        Ok(salt.try_into().map_err(|_| anyhow!("Invalid salt length"))?)
-    } else {
-        // If the salt file doesn't exist, generate it
-        let salt = derive::generate_salt();
-        fs::write(&salt_path, &salt)?;
-        Ok(salt)
+/// A derived encryption key together with the KDF and salt it was derived
+/// with, so the same parameters can be written back into the file header.
+pub struct EncryptionKey {
+    pub key: SecretKey,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+}
+
+fn push_hex(output: &mut String, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for byte in bytes {
+        output.push(DIGITS[(byte >> 4) as usize] as char);
+        output.push(DIGITS[(byte & 0x0f) as usize] as char);
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()?);
+    }
+    Some(bytes)
+}
+
+fn kdf_to_string(kdf: &Kdf) -> String {
+    match *kdf {
+        Kdf::Pbkdf2Sha256 { iterations } => format!("pbkdf2:{}", iterations),
+        Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+            format!("argon2id:{}:{}:{}", memory_kib, iterations, parallelism)
+        }
+    }
+}
+
+fn parse_kdf(text: &str) -> Option<Kdf> {
+    let fields: Vec<&str> = text.split(':').collect();
+    match fields.as_slice() {
+        ["pbkdf2", iterations] => Some(Kdf::Pbkdf2Sha256 { iterations: iterations.parse().ok()? }),
+        ["argon2id", memory_kib, iterations, parallelism] => Some(Kdf::Argon2id {
+            memory_kib: memory_kib.parse().ok()?,
+            iterations: iterations.parse().ok()?,
+            parallelism: parallelism.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+impl EncryptionKey {
+    /// Appends `<kdf> <salt hex> <key hex>`, the form in which a derived key
+    /// is handed to the agent or the kernel keyring.
+    pub fn push_fields(&self, output: &mut String) {
+        output.push_str(&kdf_to_string(&self.kdf));
+        output.push(' ');
+        push_hex(output, &self.salt);
+        output.push(' ');
+        push_hex(output, self.key.expose());
+    }
+
+    /// Parses the fields written by `push_fields`.
+    pub fn from_fields(fields: &[&str]) -> Option<EncryptionKey> {
+        let [kdf, salt, key] = fields else { return None };
+        let key_bytes = SecretBytes::new(from_hex(key)?);
+        if key_bytes.expose().len() != 32 {
+            return None;
+        }
+        let mut secret = SecretKey::zeroed();
+        secret.expose_mut().copy_from_slice(key_bytes.expose());
+        Some(EncryptionKey { key: secret, kdf: parse_kdf(kdf)?, salt: from_hex(salt)? })
     }
 }
 
-pub fn get_encryption_key() -> anyhow::Result<[u8; 32]> {
-    let salt = get_or_create_salt()?;
+fn read_header(key_name: &str) -> Result<Option<Header>, EncryptionError> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    if !encrypted_path.exists() {
+        return Ok(None);
+    }
+    let encrypted_data = fs::read(&encrypted_path)?;
+    match format::parse(&encrypted_data)? {
+        KeyFile::Legacy { .. } => Ok(None),
+        KeyFile::Versioned { header, .. } => Ok(Some(header)),
+    }
+}
+
+/// KDF and salt for a key that is being encrypted for the first time: the
+/// configured Argon2id parameters and a fresh random salt.
+fn new_key_params(config: &Config) -> anyhow::Result<(Kdf, Vec<u8>)> {
+    let kdf = config.kdf.to_kdf();
+    kdf.validate()?;
+    Ok((kdf, derive::generate_salt().to_vec()))
+}
+
+/// KDF and salt the existing encrypted file for `key_name` was written with,
+/// or fresh parameters if there is no encrypted file yet.
+fn key_params(key_name: &str, config: &Config) -> anyhow::Result<(Kdf, Vec<u8>)> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    match read_header(key_name)? {
+        Some(header) => Ok((header.kdf, header.salt)),
+        None if encrypted_path.exists() => Ok((derive::LEGACY_KDF, get_legacy_salt()?.to_vec())),
+        None => new_key_params(config),
+    }
+}
+
+/// Whether the encrypted file for `key_name` should be rewritten with a
+/// per-key salt and the current default KDF.
+pub fn needs_migration(key_name: &str) -> anyhow::Result<bool> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    if !encrypted_path.exists() {
+        return Ok(false);
+    }
+    match read_header(key_name)? {
+        None => Ok(true),
+        Some(header) => {
+            let shared_salt = get_legacy_salt().ok();
+            Ok(!matches!(header.kdf, Kdf::Argon2id { .. })
+                || shared_salt.is_some_and(|salt| header.salt == salt))
+        }
+    }
+}
+
+fn derive_encryption_key(password: &str, kdf: Kdf, salt: Vec<u8>) -> anyhow::Result<EncryptionKey> {
+    let key = derive::derive_key_from_password(password, &salt, &kdf)?;
+    Ok(EncryptionKey { key, kdf, salt })
+}
+
+/// Prompts for the password of an existing encrypted key until it verifies,
+/// giving up after the configured number of attempts.
+fn unlock_with_retries(key_name: &str, config: &Config) -> anyhow::Result<(SecretString, EncryptionKey)> {
+    let (kdf, salt) = key_params(key_name, config)?;
+    let attempts = if input::is_scripted() { 1 } else { config.password.attempts.max(1) };
+    for attempt in 1..=attempts {
+        let password = input::get_password()?;
+        // println!("Debug: Password length: {}", password.len());
+        let encryption_key = derive_encryption_key(password.expose(), kdf, salt.clone())?;
+        match verify_encryption_key(key_name, &encryption_key) {
+            Ok(()) => return Ok((password, encryption_key)),
+            Err(e) if e.is_wrong_password() && attempt < attempts => {
+                println!("{}. {} attempt(s) left.", e, attempts - attempt);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!("the last attempt always returns")
+}
+
+pub fn get_encryption_key(key_name: &str, config: &Config) -> anyhow::Result<EncryptionKey> {
+    check_key_exists(key_name)?;
+    get_encryption_key_unchecked(key_name, config)
+}
+
+/// Like `get_encryption_key`, for a key whose plaintext is not in the key
+/// directory, e.g. because `comx` just created it in a staging directory.
+pub fn get_encryption_key_unchecked(key_name: &str, config: &Config) -> anyhow::Result<EncryptionKey> {
+    // A key without an encrypted file is about to be encrypted for the first
+    // time, so a mistyped password would lock it away for good.
+    let first_encryption = !get_encrypted_key_path(key_name)?.exists();
+    if !first_encryption {
+        let (_password, encryption_key) = unlock_with_retries(key_name, config)?;
+        return Ok(encryption_key);
+    }
+
+    let (kdf, salt) = new_key_params(config)?;
     // println!("Debug: Salt for key derivation: {:?}", salt);
-    let mut password = input::get_password()?;
-    // println!("Debug: Password length: {}", password.len());
-    let encryption_key = derive::derive_key_from_password(&password, &salt);
+    println!("Key '{}' has not been encrypted yet; choose a password for it.", key_name);
+    let password = input::get_new_password(&config.password)?;
     // println!("Debug: Derived encryption key: {:?}", encryption_key);
-    password.zeroize();
-    Ok(encryption_key)
+    derive_encryption_key(password.expose(), kdf, salt)
 }
 
-pub fn encrypt_key_file(key_name: &str, key_bytes: &[u8]) -> Result<(), EncryptionError> {
+/// Encrypts `data` with a fresh nonce and returns the full key file contents.
+fn encrypt_data(data: &[u8], encryption_key: &EncryptionKey) -> Result<Vec<u8>, EncryptionError> {
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    // println!("Debug: Generated nonce: {:?}", nonce_bytes);
+
+    let key_check = derive::key_check_value(encryption_key.key.expose());
+    let header = Header::new(encryption_key.kdf, &encryption_key.salt, nonce_bytes, key_check);
+    let header_bytes = header.to_bytes();
+
+    let key = GenericArray::from_slice(encryption_key.key.expose());
+    let cipher = Aes256Gcm::new(key);
+
+    let payload = Payload { msg: data, aad: &header_bytes };
+    let ciphertext = match cipher.encrypt(nonce, payload) {
+        Ok(ct) => ct,
+        Err(e) => {
+            // println!("Debug: Encryption failed: {:?}", e);
+            return Err(EncryptionError::AesError(e.to_string()));
+        }
+    };
+    // println!("Debug: Ciphertext length: {}", ciphertext.len());
+
+    let mut encrypted_data = header_bytes;
+    encrypted_data.extend(ciphertext);
+    Ok(encrypted_data)
+}
+
+fn check_key(header: &Header, encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    if header.kdf != encryption_key.kdf || header.salt != encryption_key.salt {
+        return Err(EncryptionError::FormatError(
+            "Encryption key was derived with different parameters than the key file".to_string(),
+        ));
+    }
+    if let Some(expected) = &header.key_check {
+        let actual = derive::key_check_value(encryption_key.key.expose());
+        verify_slices_are_equal(expected, &actual).map_err(|_| EncryptionError::WrongPassword)?;
+    }
+    Ok(())
+}
+
+/// Decrypts the contents of a key file in any supported format version.
+fn decrypt_data(encrypted_data: &[u8], encryption_key: &EncryptionKey) -> Result<SecretBytes, EncryptionError> {
+    let key = GenericArray::from_slice(encryption_key.key.expose());
+    let cipher = Aes256Gcm::new(key);
+
+    let (decrypted, key_verified) = match format::parse(encrypted_data)? {
+        KeyFile::Legacy { nonce, ciphertext } => {
+            (cipher.decrypt(Nonce::from_slice(nonce), ciphertext), false)
+        }
+        KeyFile::Versioned { header, header_bytes, ciphertext } => {
+            check_key(&header, encryption_key)?;
+            match header.cipher {
+                Cipher::Aes256Gcm => {
+                    let payload = Payload { msg: ciphertext, aad: header_bytes };
+                    (cipher.decrypt(Nonce::from_slice(&header.nonce), payload), header.key_check.is_some())
+                }
+            }
+        }
+    };
+
+    match decrypted {
+        Ok(pt) => Ok(SecretBytes::new(pt)),
+        Err(e) if key_verified => {
+            // println!("Debug: Decryption failed: {:?}", e);
+            Err(EncryptionError::CorruptedFile(e.to_string()))
+        }
+        Err(_) => Err(EncryptionError::AuthenticationFailed),
+    }
+}
+
+/// Checks `encryption_key` against the encrypted file for `key_name` without
+/// writing anything. Files without a key check value are test-decrypted.
+pub fn verify_encryption_key(key_name: &str, encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    if !encrypted_path.exists() {
+        return Ok(());
+    }
+    let encrypted_data = fs::read(&encrypted_path)?;
+    if let KeyFile::Versioned { header, .. } = format::parse(&encrypted_data)? {
+        if header.key_check.is_some() {
+            return check_key(&header, encryption_key);
+        }
+    }
+    decrypt_data(&encrypted_data, encryption_key)?;
+    Ok(())
+}
+
+/// Re-reads an encrypted key file and checks it decrypts to `expected`.
+pub fn verify_round_trip(encrypted_path: &Path, expected: &[u8], encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    let written = fs::read(encrypted_path)?;
+    let decrypted = decrypt_data(&written, encryption_key)
+        .map_err(|e| EncryptionError::VerificationFailed(e.to_string()))?;
+    if decrypted.expose() != expected {
+        return Err(EncryptionError::VerificationFailed(
+            "decrypted contents differ from the original key file".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn encrypt_key_file(key_name: &str, encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
     // println!("Debug: Encrypting key file for: {}", key_name);
     let key_path = get_key_path(key_name)?;
     // println!("Debug: Key path: {:?}", key_path);
-    let encrypted_path = get_encrypted_key_path(key_name)?;
-    // println!("Debug: Encrypted key path: {:?}", encrypted_path);
     // Check if the key file exists
     if !key_path.exists() {
         // println!("Debug: Original key file not found: {:?}", key_path);
@@ -153,109 +481,423 @@ pub fn encrypt_key_file(key_name: &str, key_bytes: &[u8]) -> Result<(), Encrypti
 
     // println!("Debug: Original key file found: {:?}", key_path);
 
-    let data = fs::read(&key_path)?;
+    let data = SecretBytes::new(fs::read(&key_path)?);
     // println!("Debug: Read {} bytes from original key file", data.len());
 
-    let mut nonce_bytes = [0u8; 12];
-    rand::thread_rng().fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    // println!("Debug: Generated nonce: {:?}", nonce_bytes);
-
-    let key = GenericArray::from_slice(key_bytes);
-    let cipher = Aes256Gcm::new(key);
+    if let Err(e) = write_encrypted_key(key_name, data.expose(), encryption_key) {
+        if matches!(e, EncryptionError::VerificationFailed(_)) {
+            println!("Encrypted key file failed verification, keeping original key file: {:?}", key_path);
+        }
+        return Err(e);
+    }
 
-    let ciphertext = match cipher.encrypt(nonce, data.as_ref()) {
-        Ok(ct) => ct,
+    match cleanup::secure_remove(&key_path) {
+        Ok(_) => println!("Original key file removed: {:?}", key_path),
         Err(e) => {
-            // println!("Debug: Encryption failed: {:?}", e);
-            return Err(EncryptionError::AesError(e.to_string()));
+            println!("Failed to remove original key file: {:?}", e);
+            return Err(EncryptionError::IoError(e));
         }
-    };
-    // println!("Debug: Ciphertext length: {}", ciphertext.len());
+    }
 
-    let mut encrypted_data = nonce_bytes.to_vec();
-    encrypted_data.extend(ciphertext);
+    Ok(())
+}
+
+/// Decrypts a key into memory without writing any plaintext to disk.
+pub fn read_encrypted_key(key_name: &str, encryption_key: &EncryptionKey) -> Result<SecretBytes, EncryptionError> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    let encrypted_data = fs::read(&encrypted_path)?;
+    decrypt_data(&encrypted_data, encryption_key)
+}
+
+/// Encrypts `data` and atomically replaces the encrypted key file with it.
+pub fn write_encrypted_key(key_name: &str, data: &[u8], encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    let encrypted_data = encrypt_data(data, encryption_key)?;
 
     // println!("Debug: Encrypted key path: {:?}", encrypted_path.display());
     
     // Ensure the encrypted directory exists
     if let Some(parent) = encrypted_path.parent() {
         // println!("Debug: Creating encrypted directory: {:?}", parent);
-        fs::create_dir_all(parent)?;
+        permissions::create_private_dir(parent)?;
     }
 
-    match fs::write(&encrypted_path, &encrypted_data) {
+    // The new ciphertext is staged next to the old one, fsynced and checked
+    // to decrypt back to exactly the same bytes before it replaces anything.
+    let mut atomic = AtomicFile::create(&encrypted_path)?;
+    if let Err(e) = atomic.write_all(&encrypted_data).and_then(|_| atomic.sync()) {
+        println!("Failed to write encrypted key file: {:?}", e);
+        return Err(EncryptionError::IoError(e));
+    }
+    verify_round_trip(atomic.temp_path(), data, encryption_key)?;
+    match atomic.commit() {
         Ok(_) => println!("Encrypted key file written: {:?}", encrypted_path),
         Err(e) => {
             println!("Failed to write encrypted key file: {:?}", e);
             return Err(EncryptionError::IoError(e));
         }
     }
+    Ok(())
+}
 
-    match fs::remove_file(&key_path) {
-        Ok(_) => println!("Original key file removed: {:?}", key_path),
-        Err(e) => {
-            println!("Failed to remove original key file: {:?}", e);
-            return Err(EncryptionError::IoError(e));
+/// Encrypts `data` with `encryption_key` into a file other than a key file,
+/// e.g. the shell history, replacing it atomically.
+pub fn write_encrypted_file(path: &Path, data: &[u8], encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
+    let encrypted_data = encrypt_data(data, encryption_key)?;
+    write_atomic(path, &encrypted_data)?;
+    Ok(())
+}
+
+/// Decrypts a file written by `write_encrypted_file`.
+pub fn read_encrypted_file(path: &Path, encryption_key: &EncryptionKey) -> Result<SecretBytes, EncryptionError> {
+    decrypt_data(&fs::read(path)?, encryption_key)
+}
+
+/// Where the key material for a key currently lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFileState {
+    /// Neither the plaintext nor the encrypted file exists.
+    Missing,
+    /// Only `encrypted/<name>.enc` exists; the key is locked.
+    OnlyEncrypted,
+    /// Only `<name>.json` exists; the key has never been encrypted.
+    OnlyPlaintext,
+    /// Both exist and the ciphertext decrypts to the plaintext.
+    Matching,
+    /// Both exist but the plaintext differs from what the ciphertext holds,
+    /// e.g. after `comx` rewrote the key or an earlier crash.
+    Diverged,
+}
+
+/// Which copy wins when the plaintext and encrypted key have diverged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconcile {
+    /// Re-encrypt the plaintext over the old ciphertext.
+    KeepPlaintext,
+    /// Discard the plaintext and keep the encrypted copy.
+    KeepEncrypted,
+}
+
+pub fn key_file_state(key_name: &str, encryption_key: &EncryptionKey) -> Result<KeyFileState, EncryptionError> {
+    let key_path = get_key_path(key_name)?;
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    match (key_path.exists(), encrypted_path.exists()) {
+        (false, false) => Ok(KeyFileState::Missing),
+        (false, true) => Ok(KeyFileState::OnlyEncrypted),
+        (true, false) => Ok(KeyFileState::OnlyPlaintext),
+        (true, true) => {
+            let plaintext = SecretBytes::new(fs::read(&key_path)?);
+            let decrypted = decrypt_data(&fs::read(&encrypted_path)?, encryption_key)?;
+            let matches = plaintext.expose() == decrypted.expose();
+            Ok(if matches { KeyFileState::Matching } else { KeyFileState::Diverged })
         }
     }
-
-    Ok(())
 }
 
-pub fn decrypt_key_file(key_name: &str, key_bytes: &[u8]) -> Result<(), EncryptionError> {
+/// Makes the plaintext key available. The encrypted file is never written
+/// here, so a plaintext copy can never replace the ciphertext.
+pub fn decrypt_key_file(key_name: &str, encryption_key: &EncryptionKey) -> Result<(), EncryptionError> {
     // println!("Debug: Decrypting key file for: {}", key_name);
     let encrypted_path = get_encrypted_key_path(key_name)?;
     // println!("Debug: Encrypted key path: {:?}", encrypted_path);
     let key_path = get_key_path(key_name)?;
     // println!("Debug: Decrypted key path: {:?}", key_path);
-    if key_path.exists() {
-        let plaintext = fs::read(&key_path)?;
-        fs::write(&encrypted_path, &plaintext)?;
-        return Ok(());
-    }
-    
-    
-    // println!("Debug: Encrypting to path: {:?}", encrypted_path);
-    if !encrypted_path.exists() {
-        return Err(EncryptionError::IoError(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Encrypted key file not found: {}", encrypted_path.display()),
-        )));
+
+    match key_file_state(key_name, encryption_key)? {
+        KeyFileState::Missing => {
+            return Err(EncryptionError::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Encrypted key file not found: {}", encrypted_path.display()),
+            )));
+        }
+        KeyFileState::OnlyPlaintext => {
+            println!("Key file is not encrypted yet: {:?}", key_path);
+            return Ok(());
+        }
+        KeyFileState::Matching => return Ok(()),
+        KeyFileState::Diverged => return Err(EncryptionError::Diverged(key_name.to_string())),
+        KeyFileState::OnlyEncrypted => {}
     }
+
     let encrypted_data = fs::read(&encrypted_path)?;
     // println!("Debug: Read {} bytes from encrypted file", encrypted_data.len());
 
-    if encrypted_data.len() < 12 {
-        return Err(EncryptionError::AesError("Encrypted data too short".to_string()));
+    let plaintext = decrypt_data(&encrypted_data, encryption_key)?;
+    // println!("Debug: Decrypted plaintext length: {}", plaintext.len());
+
+    write_atomic(&key_path, plaintext.expose())?;
+    // println!("Debug: Decrypted key written to {:?}", key_path);
+
+    Ok(())
+}
+
+/// Resolves a diverged key by keeping one of the two copies. Either way the
+/// key ends up locked: only the encrypted file remains.
+pub fn reconcile_key_file(key_name: &str, encryption_key: &EncryptionKey, keep: Reconcile) -> Result<(), EncryptionError> {
+    match key_file_state(key_name, encryption_key)? {
+        KeyFileState::Diverged => {}
+        state => {
+            println!("Key '{}' does not need reconciling ({:?})", key_name, state);
+            return Ok(());
+        }
     }
 
-    let (nonce_bytes, ciphertext) = encrypted_data.split_at(12);
-    // println!("Debug: Nonce: {:?}", nonce_bytes);
-    // println!("Debug: Ciphertext length: {}", ciphertext.len());
-    let nonce = Nonce::from_slice(nonce_bytes);
+    match keep {
+        Reconcile::KeepPlaintext => encrypt_key_file(key_name, encryption_key),
+        Reconcile::KeepEncrypted => {
+            let key_path = get_key_path(key_name)?;
+            cleanup::secure_remove(&key_path)?;
+            println!("Diverged plaintext key file removed: {:?}", key_path);
+            Ok(())
+        }
+    }
+}
 
-    let key = GenericArray::from_slice(key_bytes);
-    let cipher = Aes256Gcm::new(key);
+/// Re-encrypts an existing key file with a per-key salt and the configured
+/// Argon2id parameters. The plaintext only exists in memory.
+pub fn migrate_key_file(key_name: &str, config: &Config) -> anyhow::Result<()> {
+    check_key_exists(key_name)?;
+    let encrypted_path = get_encrypted_key_path(key_name)?;
+    if !needs_migration(key_name)? {
+        println!("Key file {:?} is already up to date", encrypted_path);
+        return Ok(());
+    }
 
-    let plaintext = match cipher.decrypt(nonce, ciphertext) {
-        Ok(pt) => pt,
-        Err(e) => {
-            // println!("Debug: Decryption failed: {:?}", e);
-            return Err(EncryptionError::AesError(e.to_string()));
+    let (new_kdf, new_salt) = new_key_params(config)?;
+    let (password, old_key) = unlock_with_retries(key_name, config)?;
+    let new_key = derive_encryption_key(password.expose(), new_kdf, new_salt)?;
+
+    let encrypted_data = fs::read(&encrypted_path)?;
+    let plaintext = decrypt_data(&encrypted_data, &old_key)?;
+
+    // Verified against the plaintext before it replaces the old file.
+    write_encrypted_key(key_name, plaintext.expose(), &new_key)?;
+    println!("Key file migrated: {:?}", encrypted_path);
+    Ok(())
+}
+
+fn list_keys_with_extension(dir: &Path, extension: &str) -> Result<Vec<String>, EncryptionError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(stem.to_string());
+            }
         }
-    };
-    // println!("Debug: Decrypted plaintext length: {}", plaintext.len());
+    }
+    names.sort();
+    Ok(names)
+}
 
-    let key_path = get_key_path(key_name)?;
-    // println!("Debug: Decrypted key path: {:?}", key_path);
+/// Names of all keys that have an encrypted file in the vault.
+pub fn list_encrypted_keys() -> Result<Vec<String>, EncryptionError> {
+    list_keys_with_extension(&get_key_directory()?.join("encrypted"), "enc")
+}
 
-    fs::write(&key_path, &plaintext)?;
-    // println!("Debug: Decrypted key written to {:?}", key_path);
+/// Names of all keys with a staged marker, see `get_staged_marker_path`.
+pub fn list_staged_keys() -> Result<Vec<String>, EncryptionError> {
+    list_keys_with_extension(&get_key_directory()?.join("encrypted"), "staged")
+}
+
+/// Names of all keys with a plaintext file in the key directory.
+pub fn list_plaintext_keys() -> Result<Vec<String>, EncryptionError> {
+    list_keys_with_extension(&get_key_directory()?, "json")
+}
+
+/// What is on disk for a key, as far as can be told without its password.
+#[derive(Debug)]
+pub struct KeyStatus {
+    pub encrypted: bool,
+    pub plaintext: bool,
+    /// The plaintext was decrypted by the wrapper, not created by `comx`.
+    pub staged: bool,
+    /// `None` for keys in the headerless format or without an encrypted file.
+    pub kdf: Option<Kdf>,
+    pub needs_migration: bool,
+}
+
+pub fn key_status(key_name: &str) -> anyhow::Result<KeyStatus> {
+    Ok(KeyStatus {
+        encrypted: get_encrypted_key_path(key_name)?.exists(),
+        plaintext: get_key_path(key_name)?.exists(),
+        staged: get_staged_marker_path(key_name)?.exists(),
+        kdf: read_header(key_name)?.map(|header| header.kdf),
+        needs_migration: needs_migration(key_name)?,
+    })
+}
 
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Created in the encrypted key directory once every re-encrypted key file
+/// is in place, and removed after the backups. It tells
+/// `recover_password_change` whether an interrupted change had finished.
+const PASSWORD_CHANGE_COMMITTED: &str = ".passwd-committed";
+
+/// A key file re-encrypted under the new password, with what it must decrypt
+/// back to.
+struct RekeyedKey {
+    path: PathBuf,
+    ciphertext: Vec<u8>,
+    plaintext: SecretBytes,
+    key: EncryptionKey,
+}
+
+/// Stages every re-encrypted file on disk first and checks that each one
+/// decrypts back to its key, then moves each into place while keeping a
+/// hard-linked backup of the old ciphertext until every key has been
+/// replaced. On any failure the backups are restored so the vault is left
+/// entirely under the old password.
+fn replace_key_files(rekeyed: &[RekeyedKey]) -> Result<(), EncryptionError> {
+    let Some(dir) = rekeyed.first().and_then(|rekeyed| rekeyed.path.parent()) else { return Ok(()) };
+    let mut staged = Vec::new();
+    for rekeyed in rekeyed {
+        let mut atomic = AtomicFile::create(&rekeyed.path)?;
+        atomic.write_all(&rekeyed.ciphertext)?;
+        atomic.sync()?;
+        staged.push((&rekeyed.path, atomic));
+    }
+    // Dropping the staged files removes them, so nothing has changed yet if
+    // one of them is bad.
+    for (rekeyed, (_, atomic)) in rekeyed.iter().zip(&staged) {
+        verify_round_trip(atomic.temp_path(), rekeyed.plaintext.expose(), &rekeyed.key)?;
+    }
+
+    let mut replaced = Vec::new();
+    let mut result = Ok(());
+    for (path, atomic) in staged {
+        let backup = with_suffix(path, ".bak");
+        if let Err(e) = fs::hard_link(path, &backup) {
+            result = Err(e);
+            break;
+        }
+        replaced.push(path);
+        if let Err(e) = atomic.commit() {
+            result = Err(e);
+            break;
+        }
+    }
+
+    // Once the marker is durable the change counts as done, even if a crash
+    // interrupts removing the backups.
+    let committed = dir.join(PASSWORD_CHANGE_COMMITTED);
+    if result.is_ok() {
+        result = permissions::create_private_file(&committed).and_then(|_| sync_dir(dir));
+    }
+    if result.is_err() {
+        let _ = fs::remove_file(&committed);
+        let _ = sync_dir(dir);
+    }
+    for path in replaced {
+        let backup = with_suffix(path, ".bak");
+        if result.is_err() {
+            let _ = fs::rename(&backup, path);
+        } else {
+            let _ = fs::remove_file(&backup);
+        }
+    }
+    sync_dir(dir)?;
+    if result.is_ok() {
+        let _ = fs::remove_file(&committed);
+        let _ = sync_dir(dir);
+    }
+    Ok(result?)
+}
+
+/// Completes a password change that was interrupted while key files were
+/// being moved into place. Without the commit marker some keys may already
+/// be under the new password, so every `<name>.enc.bak` is moved back and
+/// the vault is left under the old one; with it, the leftover backups are
+/// removed.
+pub fn recover_password_change() -> Result<(), EncryptionError> {
+    let dir = get_key_directory()?.join("encrypted");
+    if !dir.exists() {
+        return Ok(());
+    }
+    let committed = dir.join(PASSWORD_CHANGE_COMMITTED);
+    let finished = committed.exists();
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.to_str().is_some_and(|path| path.ends_with(".enc.bak")) {
+            backups.push(path);
+        }
+    }
+    if backups.is_empty() && !finished {
+        return Ok(());
+    }
+
+    for backup in &backups {
+        if finished {
+            fs::remove_file(backup)?;
+        } else {
+            // Strips `.bak`, leaving `<name>.enc`.
+            fs::rename(backup, backup.with_extension(""))?;
+        }
+    }
+    sync_dir(&dir)?;
+    if finished {
+        fs::remove_file(&committed)?;
+        sync_dir(&dir)?;
+        println!("Finished an interrupted password change; all keys use the new password");
+    } else {
+        println!("Rolled back an interrupted password change; all keys still use the old password");
+    }
+    Ok(())
+}
+
+/// Re-encrypts every key in the vault under a new password. Each key is
+/// decrypted in memory with the old password first; nothing on disk changes
+/// unless all of them decrypt.
+pub fn change_password(config: &Config) -> anyhow::Result<()> {
+    let key_names = list_encrypted_keys()?;
+    if key_names.is_empty() {
+        return Err(anyhow!("No encrypted keys found"));
+    }
+
+    // Verify the old password against the first key; the rest must match it.
+    let (old_password, _) = unlock_with_retries(&key_names[0], config)?;
+    let new_password = input::get_new_password(&config.password)?;
+
+    let rekeyed = rekey_all(&key_names, &old_password, &new_password, config)?;
+
+    replace_key_files(&rekeyed).map_err(|e| anyhow!("Password change rolled back: {}", e))?;
+    for name in key_names {
+        println!("Key '{}' re-encrypted with the new password", name);
+    }
     Ok(())
 }
 
+fn rekey_all(
+    key_names: &[String],
+    old_password: &SecretString,
+    new_password: &SecretString,
+    config: &Config,
+) -> anyhow::Result<Vec<RekeyedKey>> {
+    let mut rekeyed = Vec::new();
+    for name in key_names {
+        let encrypted_path = get_encrypted_key_path(name)?;
+        let (old_kdf, old_salt) = key_params(name, config)?;
+        let (new_kdf, new_salt) = new_key_params(config)?;
+        let old_key = derive_encryption_key(old_password.expose(), old_kdf, old_salt)?;
+        let new_key = derive_encryption_key(new_password.expose(), new_kdf, new_salt)?;
+
+        let encrypted_data = fs::read(&encrypted_path)?;
+        let plaintext = decrypt_data(&encrypted_data, &old_key)
+            .map_err(|e| anyhow!("Failed to decrypt key '{}': {}", name, e))?;
+        let ciphertext = encrypt_data(plaintext.expose(), &new_key)?;
+        rekeyed.push(RekeyedKey { path: encrypted_path, ciphertext, plaintext, key: new_key });
+    }
+    Ok(rekeyed)
+}
+
 // pub fn test_key_derivation() {
     // let password = "test_password";
     // let salt = [0u8; 16]; // Example salt
@@ -0,0 +1,189 @@
+use crate::cryptography::derive::Kdf;
+use crate::cryptography::encryption::EncryptionError;
+
+// Layout of a versioned encrypted key file (all integers little endian):
+//
+//   magic       4 bytes   "CWKF"
+//   version     1 byte
+//   kdf id      1 byte    followed by the kdf parameters:
+//                         1 = PBKDF2-HMAC-SHA256: iterations (u32)
+//                         2 = Argon2id: memory KiB, iterations, parallelism (u32 each)
+//   salt len    1 byte    followed by the salt
+//   cipher id   1 byte
+//   nonce len   1 byte    followed by the nonce
+//   key check   16 bytes  version 2 and later, see `derive::key_check_value`
+//   ciphertext  rest of the file
+//
+// Everything before the ciphertext is authenticated as AEAD associated data.
+// Files written before the header existed are a bare `nonce || ciphertext`
+// blob and are read as version 0. Version 1 files have no key check value,
+// so a wrong password cannot be told apart from a corrupted file.
+
+pub const MAGIC: &[u8; 4] = b"CWKF";
+pub const CURRENT_VERSION: u8 = 2;
+pub const NONCE_LEN: usize = 12;
+pub const KEY_CHECK_LEN: usize = 16;
+
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+const CIPHER_AES_256_GCM: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    pub cipher: Cipher,
+    pub nonce: [u8; NONCE_LEN],
+    pub key_check: Option<[u8; KEY_CHECK_LEN]>,
+}
+
+/// An encrypted key file split into its parts.
+pub enum KeyFile<'a> {
+    /// Headerless `nonce || ciphertext` file, keyed with the shared salt.
+    Legacy {
+        nonce: &'a [u8],
+        ciphertext: &'a [u8],
+    },
+    Versioned {
+        header: Header,
+        header_bytes: &'a [u8],
+        ciphertext: &'a [u8],
+    },
+}
+
+impl Header {
+    pub fn new(kdf: Kdf, salt: &[u8], nonce: [u8; NONCE_LEN], key_check: [u8; KEY_CHECK_LEN]) -> Self {
+        Header {
+            version: CURRENT_VERSION,
+            kdf,
+            salt: salt.to_vec(),
+            cipher: Cipher::Aes256Gcm,
+            nonce,
+            key_check: Some(key_check),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        match self.kdf {
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                out.push(KDF_PBKDF2_SHA256);
+                out.extend_from_slice(&iterations.to_le_bytes());
+            }
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                out.push(KDF_ARGON2ID);
+                out.extend_from_slice(&memory_kib.to_le_bytes());
+                out.extend_from_slice(&iterations.to_le_bytes());
+                out.extend_from_slice(&parallelism.to_le_bytes());
+            }
+        }
+        out.push(self.salt.len() as u8);
+        out.extend_from_slice(&self.salt);
+        match self.cipher {
+            Cipher::Aes256Gcm => out.push(CIPHER_AES_256_GCM),
+        }
+        out.push(NONCE_LEN as u8);
+        out.extend_from_slice(&self.nonce);
+        if let Some(key_check) = &self.key_check {
+            out.extend_from_slice(key_check);
+        }
+        out
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EncryptionError> {
+        if self.data.len() - self.pos < len {
+            return Err(EncryptionError::FormatError("Truncated header".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, EncryptionError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, EncryptionError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<KeyFile<'_>, EncryptionError> {
+    if !data.starts_with(MAGIC) {
+        if data.len() < NONCE_LEN {
+            return Err(EncryptionError::AesError("Encrypted data too short".to_string()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        return Ok(KeyFile::Legacy { nonce, ciphertext });
+    }
+
+    let mut reader = Reader { data, pos: MAGIC.len() };
+    let version = reader.u8()?;
+    if version != 1 && version != 2 {
+        return Err(EncryptionError::FormatError(format!(
+            "Unsupported key file version {}",
+            version
+        )));
+    }
+
+    let kdf = match reader.u8()? {
+        KDF_PBKDF2_SHA256 => Kdf::Pbkdf2Sha256 { iterations: reader.u32()? },
+        KDF_ARGON2ID => Kdf::Argon2id {
+            memory_kib: reader.u32()?,
+            iterations: reader.u32()?,
+            parallelism: reader.u32()?,
+        },
+        other => {
+            return Err(EncryptionError::FormatError(format!("Unknown KDF id {}", other)));
+        }
+    };
+    kdf.validate().map_err(|e| EncryptionError::FormatError(e.to_string()))?;
+
+    let salt_len = reader.u8()? as usize;
+    let salt = reader.take(salt_len)?.to_vec();
+
+    let cipher = match reader.u8()? {
+        CIPHER_AES_256_GCM => Cipher::Aes256Gcm,
+        other => {
+            return Err(EncryptionError::FormatError(format!("Unknown cipher id {}", other)));
+        }
+    };
+
+    let nonce_len = reader.u8()? as usize;
+    if nonce_len != NONCE_LEN {
+        return Err(EncryptionError::FormatError(format!("Invalid nonce length {}", nonce_len)));
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(reader.take(NONCE_LEN)?);
+
+    let key_check = if version >= 2 {
+        let mut key_check = [0u8; KEY_CHECK_LEN];
+        key_check.copy_from_slice(reader.take(KEY_CHECK_LEN)?);
+        Some(key_check)
+    } else {
+        None
+    };
+
+    let (header_bytes, ciphertext) = data.split_at(reader.pos);
+    Ok(KeyFile::Versioned {
+        header: Header { version, kdf, salt, cipher, nonce, key_check },
+        header_bytes,
+        ciphertext,
+    })
+}
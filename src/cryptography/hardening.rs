@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Secret buffers are locked into RAM with mlock so they are never written to
+// swap. Locks work on whole pages and do not nest, so pages shared by several
+// secrets are reference counted and only unlocked once the last one is gone.
+
+static LOCK_SECRETS: AtomicBool = AtomicBool::new(false);
+static LOCK_FAILURE_REPORTED: AtomicBool = AtomicBool::new(false);
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let size = page_size();
+    let start = ptr as usize / size * size;
+    let end = (ptr as usize + len).div_ceil(size) * size;
+    (start..end).step_by(size)
+}
+
+fn report_lock_failure(error: io::Error) {
+    // Stop trying, every further attempt would fail the same way.
+    LOCK_SECRETS.store(false, Ordering::Relaxed);
+    if !LOCK_FAILURE_REPORTED.swap(true, Ordering::Relaxed) {
+        println!("Warning: could not lock secret memory, it may be swapped to disk: {}", error);
+    }
+}
+
+/// Locks the pages holding `len` bytes at `ptr` into RAM if hardening is on.
+pub fn lock_memory(ptr: *const u8, len: usize) {
+    if len == 0 || !LOCK_SECRETS.load(Ordering::Relaxed) {
+        return;
+    }
+    let size = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for page in pages(ptr, len) {
+        if let Some(count) = locked.get_mut(&page) {
+            *count += 1;
+            continue;
+        }
+        // SAFETY: the page belongs to a live allocation and mlock does not
+        // touch its contents.
+        if unsafe { libc::mlock(page as *const libc::c_void, size) } != 0 {
+            report_lock_failure(io::Error::last_os_error());
+            return;
+        }
+        locked.insert(page, 1);
+    }
+}
+
+/// Releases pages locked by `lock_memory` once no other secret uses them.
+/// Must be called before the memory is freed.
+pub fn unlock_memory(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let size = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if locked.is_empty() {
+        return;
+    }
+    for page in pages(ptr, len) {
+        let Some(count) = locked.get_mut(&page) else { continue };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: see lock_memory.
+            unsafe { libc::munlock(page as *const libc::c_void, size) };
+        }
+    }
+}
+
+/// Disables core dumps, makes the process non-dumpable (which also keeps
+/// other processes of the same user from attaching with ptrace or reading
+/// its memory through /proc) and turns on locking of secret buffers.
+/// Returns a description of every measure that could not be applied.
+pub fn harden_process() -> Vec<String> {
+    let mut failures = Vec::new();
+
+    let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: no_core is a valid rlimit.
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) } != 0 {
+        failures.push(format!("disabling core dumps: {}", io::Error::last_os_error()));
+    }
+
+    // SAFETY: PR_SET_DUMPABLE takes a single integer argument.
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        failures.push(format!("marking the process non-dumpable: {}", io::Error::last_os_error()));
+    }
+
+    // Try a page up front so a low RLIMIT_MEMLOCK is reported at startup
+    // rather than when the first secret is created.
+    let probe = vec![0u8; page_size()];
+    // SAFETY: probe is a live allocation of the given length.
+    if unsafe { libc::mlock(probe.as_ptr() as *const libc::c_void, probe.len()) } != 0 {
+        failures.push(format!("locking secret memory: {}", io::Error::last_os_error()));
+    } else {
+        // SAFETY: as above.
+        unsafe { libc::munlock(probe.as_ptr() as *const libc::c_void, probe.len()) };
+        LOCK_SECRETS.store(true, Ordering::Relaxed);
+    }
+
+    failures
+}
@@ -1,27 +1,201 @@
 use rpassword::read_password;
-use std::io::stdin;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{stdin, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use crate::config::{PasswordConfig, Policy};
+use crate::cryptography::encryption;
+use crate::cryptography::secret::{SecretBytes, SecretString};
+use crate::cryptography::pinentry;
+use crate::cryptography::strength;
+
+/// A non-interactive source of passwords for scripts and supervisors. It is
+/// read once at startup and holds one password per line, handed out in the
+/// order the wrapper asks for them (e.g. old, then new password for `passwd`).
+pub enum PasswordSource {
+    Fd(i32),
+    File(PathBuf),
+    Stdin,
+    /// Name of an environment variable. It is removed from the environment
+    /// after reading so `comx` does not inherit it.
+    Env(String),
+}
+
+// Passwords read from a `PasswordSource`. `None` means prompt on the TTY.
+static SCRIPTED_PASSWORDS: Mutex<Option<VecDeque<SecretString>>> = Mutex::new(None);
+
+// Program used for hidden prompts instead of the terminal, see `use_pinentry`.
+static PINENTRY_PROGRAM: Mutex<Option<String>> = Mutex::new(None);
+
+// Longest password source that is read.
+const MAX_PASSWORD_INPUT: usize = 4096;
+
+fn read_secret(reader: impl Read) -> anyhow::Result<SecretString> {
+    let buffer = SecretBytes::read_bounded(reader, MAX_PASSWORD_INPUT)?;
+    let text = std::str::from_utf8(buffer.expose())
+        .map_err(|_| anyhow::anyhow!("Password input is not valid UTF-8"))?;
+    Ok(SecretString::new(text.to_string()))
+}
+
+/// Reads all passwords from `source`; later password prompts take them from
+/// there instead of the terminal.
+pub fn use_password_source(source: &PasswordSource) -> anyhow::Result<()> {
+    let input = match source {
+        PasswordSource::Fd(fd) => {
+            // Standard input, output and error are used for other things, and
+            // the File below closes the descriptor once read.
+            if (0..=2).contains(fd) {
+                return Err(anyhow::anyhow!(
+                    "--password-fd cannot be {}; use --password-stdin to read from standard input",
+                    fd
+                ));
+            }
+            // SAFETY: F_GETFD only reads the descriptor flags.
+            if *fd < 0 || unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                return Err(anyhow::anyhow!("Password file descriptor {} is not open", fd));
+            }
+            // SAFETY: the descriptor is open, was handed to us for this
+            // purpose and is not used anywhere else.
+            read_secret(unsafe { File::from_raw_fd(*fd) })?
+        }
+        PasswordSource::File(path) => {
+            let file = File::open(path)
+                .map_err(|e| anyhow::anyhow!("Cannot open password file {}: {}", path.display(), e))?;
+            if file.metadata()?.mode() & 0o077 != 0 {
+                return Err(anyhow::anyhow!(
+                    "Password file {} is accessible by other users; chmod 600 it",
+                    path.display()
+                ));
+            }
+            read_secret(file)?
+        }
+        PasswordSource::Stdin => read_secret(stdin().lock())?,
+        PasswordSource::Env(name) => {
+            let value = std::env::var(name)
+                .map_err(|_| anyhow::anyhow!("Environment variable {} is not set", name))?;
+            std::env::remove_var(name);
+            SecretString::new(value)
+        }
+    };
+
+    let passwords = input
+        .expose()
+        .lines()
+        .map(|line| SecretString::new(line.to_string()))
+        .collect();
+    if let Ok(mut scripted) = SCRIPTED_PASSWORDS.lock() {
+        *scripted = Some(passwords);
+    }
+    Ok(())
+}
+
+/// Whether passwords come from a `PasswordSource`, in which case a wrong one
+/// cannot be retried.
+pub fn is_scripted() -> bool {
+    SCRIPTED_PASSWORDS.lock().map(|scripted| scripted.is_some()).unwrap_or(false)
+}
+
+fn next_scripted_password() -> Option<anyhow::Result<SecretString>> {
+    let mut scripted = SCRIPTED_PASSWORDS.lock().ok()?;
+    let passwords = scripted.as_mut()?;
+    Some(passwords.pop_front().ok_or_else(|| anyhow::anyhow!("Not enough passwords in the password input")))
+}
 
 
 pub fn get_key_name() -> anyhow::Result<String> {
     println!("Please enter your key name:");
     let mut key_name = String::new();
     stdin().read_line(&mut key_name)?;
-    Ok(key_name.trim().to_string())
+    let key_name = key_name.trim().to_string();
+    encryption::check_key_name(&key_name)?;
+    Ok(key_name)
+}
+
+/// Asks for passwords and mnemonics through the pinentry `program`.
+pub fn use_pinentry(program: &str) {
+    if let Ok(mut pinentry) = PINENTRY_PROGRAM.lock() {
+        *pinentry = Some(program.to_string());
+    }
+}
+
+/// Reads a secret through pinentry if enabled, otherwise from the terminal
+/// after printing `description`.
+fn read_hidden(description: &str, prompt: &str) -> anyhow::Result<SecretString> {
+    let program = PINENTRY_PROGRAM.lock().ok().and_then(|program| program.clone());
+    match program {
+        Some(program) => pinentry::get_pin(&program, description, prompt),
+        None => {
+            println!("{} (input will be hidden):", description);
+            Ok(SecretString::new(read_password()?))
+        }
+    }
 }
 
-pub fn get_password() -> anyhow::Result<String> {
+pub fn get_password() -> anyhow::Result<SecretString> {
     // if let Ok(password) = std::env::var("COMX_PASSWORD") {
     //     if !password.is_empty() {
     //         return Ok(password);
     //     }
     // }
-    println!("Please enter your password (input will be hidden):");
-    let password = read_password()?;
-    Ok(password)
+    if let Some(password) = next_scripted_password() {
+        return password;
+    }
+    read_hidden("Please enter your password", "Password:")
+}
+
+const NEW_PASSWORD_ATTEMPTS: usize = 3;
+
+/// Checks a new password against the configured strength policy. Returns
+/// false if it has to be refused.
+fn check_strength(password: &str, policy: &PasswordConfig) -> bool {
+    let estimate = strength::estimate(password);
+    if estimate.score >= policy.min_score {
+        return true;
+    }
+    println!(
+        "Password strength {}/4 is below the required {}/4 (about 10^{:.0} guesses).",
+        estimate.score, policy.min_score, estimate.guesses_log10
+    );
+    if let Some(feedback) = estimate.feedback {
+        println!("{}", feedback);
+    }
+    match policy.policy {
+        Policy::Refuse => false,
+        Policy::Warn => {
+            println!("Warning: continuing with a weak password.");
+            true
+        }
+    }
+}
+
+/// Prompts for a new password twice and only returns it if both entries match
+/// and it passes the strength policy. A scripted password is not confirmed.
+pub fn get_new_password(policy: &PasswordConfig) -> anyhow::Result<SecretString> {
+    if let Some(password) = next_scripted_password() {
+        let password = password?;
+        if !check_strength(password.expose(), policy) {
+            return Err(anyhow::anyhow!("New password from the password input was refused"));
+        }
+        return Ok(password);
+    }
+    for _ in 0..NEW_PASSWORD_ATTEMPTS {
+        let password = read_hidden("Please enter the new password", "New password:")?;
+        if !check_strength(password.expose(), policy) {
+            continue;
+        }
+        let confirmation = read_hidden("Please confirm the new password", "Repeat:")?;
+        if password.expose() == confirmation.expose() {
+            return Ok(password);
+        }
+        println!("Passwords do not match.");
+    }
+    Err(anyhow::anyhow!("No acceptable password entered"))
 }
 
-pub fn get_mnemonic() -> anyhow::Result<String> {
-    println!("Please enter your mnemonic (input will be hidden):");
-    let mnemonic = read_password()?;
-    Ok(mnemonic.trim().to_string())
+pub fn get_mnemonic() -> anyhow::Result<SecretString> {
+    let mnemonic = read_hidden("Please enter your mnemonic", "Mnemonic:")?;
+    Ok(SecretString::new(mnemonic.expose().trim().to_string()))
 }
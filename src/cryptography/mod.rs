@@ -1,4 +1,12 @@
+pub mod atomic;
 pub mod derive;
 pub mod encryption;
+pub mod format;
 pub mod input;
-pub mod cleanup;
\ No newline at end of file
+pub mod permissions;
+pub mod strength;
+pub mod cleanup;
+pub mod staging;
+pub mod secret;
+pub mod hardening;
+pub mod pinentry;
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use crate::config::{PermissionsConfig, Policy};
+use crate::cryptography::encryption;
+
+pub const DIR_MODE: u32 = 0o700;
+pub const FILE_MODE: u32 = 0o600;
+
+/// Creates `path` and any missing parents readable only by the current user.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(DIR_MODE).create(path)
+}
+
+/// Creates a new file readable only by the current user. Fails if it exists.
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(FILE_MODE)
+        .open(path)
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() }
+}
+
+fn check_path(path: &Path, problems: &mut Vec<String>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.uid() != current_uid() {
+        problems.push(format!("{} is owned by uid {}", path.display(), metadata.uid()));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        let expected = if metadata.is_dir() { DIR_MODE } else { FILE_MODE };
+        problems.push(format!(
+            "{} has mode {:o}, expected {:o}",
+            path.display(),
+            metadata.mode() & 0o777,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Lists vault directories and files that other users can access or that
+/// belong to another user.
+pub fn vault_problems() -> io::Result<Vec<String>> {
+    let key_directory = encryption::get_key_directory().map_err(io::Error::other)?;
+    let mut paths: Vec<PathBuf> = vec![key_directory.clone()];
+    for dir in [key_directory.clone(), key_directory.join("encrypted")] {
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            paths.push(entry?.path());
+        }
+    }
+
+    let mut problems = Vec::new();
+    for path in paths {
+        check_path(&path, &mut problems)?;
+    }
+    Ok(problems)
+}
+
+/// Startup check of the key directory. Depending on the configured policy,
+/// problems are reported as warnings or abort the program.
+pub fn check_vault(config: &PermissionsConfig) -> anyhow::Result<()> {
+    let problems = vault_problems()?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("Insecure key vault: {}", problem);
+    }
+    println!("Fix with: chmod -R go-rwx ~/.commune/key");
+    match config.policy {
+        Policy::Refuse => Err(anyhow::anyhow!("Refusing to use a key vault that other users can access")),
+        Policy::Warn => Ok(()),
+    }
+}
+
+/// Checks that the config file and the directory holding it belong to the
+/// current user and that no one else can write to them, since the config
+/// names programs to run, e.g. `pinentry.program`. Unlike the vault check
+/// this always refuses: the policy itself would come from the file.
+pub fn check_config(path: &Path) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for path in path.parent().into_iter().chain([path]) {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.uid() != current_uid() {
+            problems.push(format!("{} is owned by uid {}", path.display(), metadata.uid()));
+        }
+        if metadata.file_type().is_symlink() {
+            problems.push(format!("{} is a symlink", path.display()));
+        } else if metadata.mode() & 0o022 != 0 {
+            problems.push(format!(
+                "{} is writable by other users (mode {:o})",
+                path.display(),
+                metadata.mode() & 0o777
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("Insecure config: {}", problem);
+    }
+    println!("Fix with: chmod go-w ~/.commune ~/.commune/cli_wrapper.toml");
+    Err(anyhow::anyhow!("Refusing to load a config file that other users can change"))
+}
@@ -0,0 +1,171 @@
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use anyhow::anyhow;
+use crate::cryptography::secret::{SecretBytes, SecretString};
+
+// A minimal client for the Assuan protocol spoken by pinentry programs
+// (pinentry-gnome3, pinentry-qt, pinentry-curses, ...). Every request is a
+// single line answered by `OK` or `ERR <code> <description>`; GETPIN sends
+// the entered text as percent-encoded `D` lines before its `OK`.
+
+// Assuan lines are at most 1000 bytes.
+const MAX_LINE: usize = 1000;
+
+// GPG_ERR_CANCELED and GPG_ERR_NOT_CONFIRMED, reported when the dialog is
+// closed without entering anything.
+const CANCELLED: &[&str] = &["83886179", "83886194"];
+
+struct Pinentry {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+/// Escapes the characters Assuan does not allow in a parameter.
+fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            '\r' => encoded.push_str("%0D"),
+            '\n' => encoded.push_str("%0A"),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+/// Appends the percent-decoded `data` to `output`, which is never grown
+/// beyond its capacity.
+fn decode_into(data: &[u8], output: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut i = 0;
+    while i < data.len() {
+        if output.len() == output.capacity() {
+            return Err(anyhow!("Overlong response from pinentry"));
+        }
+        if data[i] == b'%' {
+            let hex = data
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid percent escape from pinentry"))?;
+            output.push(hex);
+            i += 3;
+        } else {
+            output.push(data[i]);
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+fn terminal_name() -> Option<String> {
+    // SAFETY: ttyname returns NULL or a pointer to a NUL-terminated string
+    // that stays valid until the next call.
+    let name = unsafe { libc::ttyname(0) };
+    if name.is_null() {
+        return None;
+    }
+    // SAFETY: checked for NULL above.
+    Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned())
+}
+
+impl Pinentry {
+    fn spawn(program: &str) -> anyhow::Result<Pinentry> {
+        let mut child = Command::new(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start pinentry program '{}': {}", program, e))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("pinentry has no stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("pinentry has no stdout"))?;
+        let mut pinentry = Pinentry { child, stdin, stdout };
+        pinentry.expect_ok()?;
+        Ok(pinentry)
+    }
+
+    /// Reads one response line byte by byte, without the newline.
+    fn read_line(&mut self) -> anyhow::Result<SecretBytes> {
+        SecretBytes::fill(MAX_LINE, |line| {
+            let mut byte = [0u8; 1];
+            loop {
+                if self.stdout.read(&mut byte)? == 0 {
+                    return Err(anyhow!("pinentry exited unexpectedly"));
+                }
+                if byte[0] == b'\n' {
+                    return Ok(());
+                }
+                if line.len() == MAX_LINE {
+                    return Err(anyhow!("Overlong line from pinentry"));
+                }
+                line.push(byte[0]);
+            }
+        })
+    }
+
+    /// Reads responses up to the closing `OK`, collecting any data lines.
+    fn read_response(&mut self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        loop {
+            let line = self.read_line()?;
+            let line = line.expose();
+            if line == b"OK" || line.starts_with(b"OK ") {
+                return Ok(());
+            }
+            if let Some(rest) = line.strip_prefix(b"D ") {
+                decode_into(rest, data)?;
+            } else if let Some(rest) = line.strip_prefix(b"ERR ") {
+                let rest = String::from_utf8_lossy(rest);
+                if CANCELLED.iter().any(|code| rest.starts_with(code)) {
+                    return Err(anyhow!("Password entry cancelled"));
+                }
+                return Err(anyhow!("pinentry error: {}", rest));
+            }
+            // Status (`S`) and comment (`#`) lines carry nothing we need.
+        }
+    }
+
+    fn expect_ok(&mut self) -> anyhow::Result<()> {
+        self.read_response(&mut Vec::with_capacity(MAX_LINE))
+    }
+
+    fn command(&mut self, command: &str) -> anyhow::Result<()> {
+        self.stdin.write_all(command.as_bytes())?;
+        self.stdin.write_all(b"\n")?;
+        self.stdin.flush()?;
+        self.expect_ok()
+    }
+
+    fn get_pin(&mut self) -> anyhow::Result<SecretString> {
+        self.stdin.write_all(b"GETPIN\n")?;
+        self.stdin.flush()?;
+        let pin = SecretBytes::fill(MAX_LINE, |pin| self.read_response(pin))?;
+        let pin = std::str::from_utf8(pin.expose()).map_err(|_| anyhow!("pinentry returned invalid UTF-8"))?;
+        Ok(SecretString::new(pin.to_string()))
+    }
+}
+
+impl Drop for Pinentry {
+    fn drop(&mut self) {
+        let _ = self.stdin.write_all(b"BYE\n");
+        let _ = self.child.wait();
+    }
+}
+
+/// Asks for a secret through the pinentry `program`. `description` is the
+/// explanatory text of the dialog and `prompt` the label of the input field.
+pub fn get_pin(program: &str, description: &str, prompt: &str) -> anyhow::Result<SecretString> {
+    let mut pinentry = Pinentry::spawn(program)?;
+    if let Some(tty) = terminal_name() {
+        // Needed by pinentry-curses; graphical ones ignore it.
+        pinentry.command(&format!("OPTION ttyname={}", encode(&tty)))?;
+        if let Ok(term) = std::env::var("TERM") {
+            pinentry.command(&format!("OPTION ttytype={}", encode(&term)))?;
+        }
+    }
+    pinentry.command("SETTITLE cli-wrapper")?;
+    pinentry.command(&format!("SETDESC {}", encode(description)))?;
+    pinentry.command(&format!("SETPROMPT {}", encode(prompt)))?;
+    pinentry.get_pin()
+}
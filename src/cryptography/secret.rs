@@ -0,0 +1,130 @@
+use std::fmt;
+use std::io::{self, Read};
+use zeroize::Zeroize;
+use crate::cryptography::hardening::{lock_memory, unlock_memory};
+
+// Containers for key material. Their contents are zeroized when dropped and
+// never appear in `Debug` output. There is deliberately no `Display`, `Clone`
+// or `Deref`: the contents are only reachable through `expose`, which keeps
+// every use of a secret visible at the call site. With hardening enabled the
+// buffers are also locked into RAM; they live on the heap so that moving a
+// secret does not leave the locked pages behind.
+//
+// A `Vec` or `String` that grows moves its contents to a new allocation and
+// frees the old one without wiping it. Secrets that are read from a pipe,
+// socket or the kernel, or assembled from parts, are therefore put into a
+// buffer that is allocated at its final size up front, with `SecretBytes::fill`
+// and `SecretString::build`, and the reader or builder is bounded by that size.
+
+/// A derived 256-bit encryption key.
+pub struct SecretKey(Box<[u8; 32]>);
+
+impl SecretKey {
+    pub fn zeroed() -> SecretKey {
+        let key = Box::new([0u8; 32]);
+        lock_memory(key.as_ptr(), key.len());
+        SecretKey(key)
+    }
+
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.len());
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+/// Decrypted key file contents.
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        lock_memory(bytes.as_ptr(), bytes.capacity());
+        SecretBytes(bytes)
+    }
+
+    /// Lets `fill` write into an empty buffer with room for `capacity` bytes
+    /// and wraps it, also when `fill` fails so partial contents are wiped.
+    /// `fill` must not write more than `capacity` bytes.
+    pub fn fill<E>(capacity: usize, fill: impl FnOnce(&mut Vec<u8>) -> Result<(), E>) -> Result<SecretBytes, E> {
+        let mut buffer = Vec::with_capacity(capacity);
+        let allocation = buffer.as_ptr();
+        let result = fill(&mut buffer);
+        debug_assert_eq!(buffer.as_ptr(), allocation, "secret buffer was reallocated");
+        let buffer = SecretBytes::new(buffer);
+        result.map(|()| buffer)
+    }
+
+    /// Reads `reader` to its end, but at most `limit` bytes.
+    pub fn read_bounded(reader: impl Read, limit: usize) -> io::Result<SecretBytes> {
+        SecretBytes::fill(limit, |buffer| reader.take(limit as u64).read_to_end(buffer).map(drop))
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.capacity());
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {} bytes])", self.0.len())
+    }
+}
+
+/// A password or mnemonic.
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(string: String) -> SecretString {
+        lock_memory(string.as_ptr(), string.capacity());
+        SecretString(string)
+    }
+
+    /// Lets `build` append to an empty string with room for `capacity`
+    /// bytes, see `SecretBytes::fill`.
+    pub fn build(capacity: usize, build: impl FnOnce(&mut String)) -> SecretString {
+        let mut string = String::with_capacity(capacity);
+        let allocation = string.as_ptr();
+        build(&mut string);
+        debug_assert_eq!(string.as_ptr(), allocation, "secret buffer was reallocated");
+        SecretString::new(string)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.capacity());
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
@@ -0,0 +1,282 @@
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use dirs::home_dir;
+use crate::config::StagingMode;
+use crate::cryptography::atomic::random_hex;
+use crate::cryptography::cleanup::{self, KeyFileGuard};
+use crate::cryptography::encryption::{self, EncryptionError, EncryptionKey, KeyFileState};
+use crate::cryptography::permissions;
+use crate::cryptography::secret::SecretBytes;
+
+// In memory mode the decrypted key never touches a persistent filesystem.
+// `comx` runs with HOME set to a private directory on tmpfs that mirrors the
+// real home directory through symlinks, except that `.commune/key` is a real
+// directory holding nothing but the staged key.
+
+const STAGING_PREFIX: &str = "cli_wrapper-";
+
+// Staging directories that currently hold a decrypted key. The signal
+// handler and panic hook remove these, since neither runs destructors.
+static STAGING_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn is_tmpfs(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statfs only writes into `stat`, which is plain old data.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL-terminated and stat is a valid out pointer.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type == libc::TMPFS_MAGIC)
+}
+
+/// Places where staging directories are created, in order of preference.
+fn staging_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        roots.push(PathBuf::from(runtime_dir));
+    }
+    roots.push(PathBuf::from("/dev/shm"));
+    roots
+}
+
+fn staging_root() -> Result<PathBuf, EncryptionError> {
+    for root in staging_roots() {
+        if root.is_dir() && is_tmpfs(&root).unwrap_or(false) {
+            return Ok(root);
+        }
+    }
+    Err(EncryptionError::IoError(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "No tmpfs available for in-memory staging (tried $XDG_RUNTIME_DIR and /dev/shm)",
+    )))
+}
+
+/// Symlinks every entry of `real` into `fake`, except the ones in `skip`.
+fn mirror_dir(real: &Path, fake: &Path, skip: &[&str]) -> io::Result<()> {
+    if !real.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(real)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if skip.iter().any(|skipped| name == *skipped) {
+            continue;
+        }
+        symlink(entry.path(), fake.join(&name))?;
+    }
+    Ok(())
+}
+
+fn remove_staging_dir(dir: &Path) -> io::Result<()> {
+    let key_dir = dir.join(".commune").join("key");
+    if key_dir.is_dir() {
+        let files: Vec<PathBuf> = fs::read_dir(&key_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        for path in files {
+            if fs::symlink_metadata(&path)?.is_file() {
+                cleanup::secure_remove(&path)?;
+            }
+        }
+    }
+    fs::remove_dir_all(dir)
+}
+
+/// Removes the staging directories of this process. Used where destructors
+/// do not run.
+pub fn remove_registered_dirs() {
+    let dirs = match STAGING_DIRS.lock() {
+        Ok(dirs) => dirs.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    for dir in dirs {
+        let _ = remove_staging_dir(&dir);
+    }
+}
+
+fn process_alive(pid: i32) -> bool {
+    // SAFETY: signal 0 performs no action, it only checks the pid.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Removes staging directories left by runs that were killed. Changes `comx`
+/// made to a key in them before that are lost.
+pub fn remove_stale_staging_dirs() -> io::Result<()> {
+    for root in staging_roots() {
+        let entries = match fs::read_dir(&root) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let pid = name
+                .to_string_lossy()
+                .strip_prefix(STAGING_PREFIX)
+                .and_then(|rest| rest.split('-').next())
+                .and_then(|pid| pid.parse::<i32>().ok());
+            let pid = match pid {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if entry.metadata()?.uid() != permissions::current_uid() || process_alive(pid) {
+                continue;
+            }
+            println!("Removing staged key left in memory by a previous run: {}", entry.path().display());
+            remove_staging_dir(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// A key decrypted into a private tmpfs directory for the duration of a
+/// command. Dropping it removes the directory.
+pub struct MemoryStage {
+    key_name: String,
+    home: PathBuf,
+    key_path: PathBuf,
+    original: SecretBytes,
+}
+
+impl MemoryStage {
+    pub fn stage(key_name: &str, encryption_key: &EncryptionKey) -> Result<MemoryStage, EncryptionError> {
+        match encryption::key_file_state(key_name, encryption_key)? {
+            KeyFileState::OnlyEncrypted => {}
+            // Take the plaintext off the disk before staging it in memory.
+            KeyFileState::OnlyPlaintext | KeyFileState::Matching => {
+                encryption::encrypt_key_file(key_name, encryption_key)?;
+            }
+            KeyFileState::Diverged => return Err(EncryptionError::Diverged(key_name.to_string())),
+            KeyFileState::Missing => {
+                return Err(EncryptionError::IoError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Key not found: {}", key_name),
+                )));
+            }
+        }
+
+        let mut stage = MemoryStage::empty(key_name)?;
+        stage.original = encryption::read_encrypted_key(key_name, encryption_key)?;
+        permissions::create_private_file(&stage.key_path)?.write_all(stage.original.expose())?;
+        Ok(stage)
+    }
+
+    /// A staging directory without the key, for a `comx` command that
+    /// creates it. `sync` then encrypts whatever `comx` wrote.
+    pub fn empty(key_name: &str) -> Result<MemoryStage, EncryptionError> {
+        let real_home = home_dir()
+            .ok_or_else(|| EncryptionError::IoError(io::Error::new(io::ErrorKind::NotFound, "Home directory not found")))?;
+        let home = staging_root()?.join(format!("{}{}-{}", STAGING_PREFIX, std::process::id(), random_hex(6)));
+
+        // Not recursive, so an existing directory planted by someone else is
+        // never used.
+        DirBuilder::new().mode(permissions::DIR_MODE).create(&home)?;
+        if let Ok(mut dirs) = STAGING_DIRS.lock() {
+            dirs.push(home.clone());
+        }
+        let key_file_name = encryption::get_key_path(key_name)?
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("{}.json", key_name)));
+        let stage = MemoryStage {
+            key_name: key_name.to_string(),
+            key_path: home.join(".commune").join("key").join(key_file_name),
+            home,
+            original: SecretBytes::new(Vec::new()),
+        };
+
+        let commune = stage.home.join(".commune");
+        permissions::create_private_dir(&commune.join("key"))?;
+        mirror_dir(&real_home, &stage.home, &[".commune"])?;
+        mirror_dir(&real_home.join(".commune"), &commune, &["key"])?;
+        Ok(stage)
+    }
+
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// Re-encrypts the key if `comx` changed it, and moves key files `comx`
+    /// created next to it into the real key directory so they are not lost.
+    /// Returns whether the encrypted file was rewritten.
+    pub fn sync(&mut self, encryption_key: &EncryptionKey) -> Result<bool, EncryptionError> {
+        let mut changed = false;
+        if self.key_path.exists() {
+            let current = SecretBytes::new(fs::read(&self.key_path)?);
+            if current.expose() != self.original.expose() {
+                encryption::write_encrypted_key(&self.key_name, current.expose(), encryption_key)?;
+                self.original = current;
+                changed = true;
+            }
+        }
+
+        let key_directory = encryption::get_key_directory()?;
+        for entry in fs::read_dir(self.key_path.parent().unwrap_or(&self.home))? {
+            let path = entry?.path();
+            if path == self.key_path || !fs::symlink_metadata(&path)?.is_file() {
+                continue;
+            }
+            let Some(file_name) = path.file_name() else { continue };
+            let target = key_directory.join(file_name);
+            if target.exists() {
+                println!("Discarding {:?} created by comx: {:?} already exists", file_name, target);
+            } else {
+                let data = SecretBytes::new(fs::read(&path)?);
+                permissions::create_private_file(&target)?.write_all(data.expose())?;
+                println!("Key file created by comx saved unencrypted to {:?}; encrypt it with `cli-wrapper lock`", target);
+            }
+            cleanup::secure_remove(&path)?;
+        }
+        Ok(changed)
+    }
+}
+
+impl Drop for MemoryStage {
+    fn drop(&mut self) {
+        let _ = remove_staging_dir(&self.home);
+        if let Ok(mut dirs) = STAGING_DIRS.lock() {
+            dirs.retain(|dir| dir != &self.home);
+        }
+    }
+}
+
+/// A key decrypted for the duration of a command, on disk or in memory
+/// depending on the configured staging mode.
+pub enum StagedKey {
+    Disk(KeyFileGuard),
+    Memory(MemoryStage),
+}
+
+impl StagedKey {
+    pub fn stage(key_name: &str, encryption_key: &EncryptionKey, mode: StagingMode) -> Result<StagedKey, EncryptionError> {
+        match mode {
+            StagingMode::Disk => Ok(StagedKey::Disk(KeyFileGuard::stage(key_name, encryption_key)?)),
+            StagingMode::Memory => Ok(StagedKey::Memory(MemoryStage::stage(key_name, encryption_key)?)),
+        }
+    }
+
+    /// The HOME `comx` has to run with to find the staged key.
+    pub fn home(&self) -> Option<&Path> {
+        match self {
+            StagedKey::Disk(_) => None,
+            StagedKey::Memory(stage) => Some(stage.home()),
+        }
+    }
+
+    /// Writes changes `comx` made to the key back to the encrypted file and
+    /// leaves the key staged. Returns whether the encrypted file was
+    /// rewritten; it is left untouched if the key did not change.
+    pub fn sync(&mut self, encryption_key: &EncryptionKey) -> Result<bool, EncryptionError> {
+        match self {
+            StagedKey::Disk(guard) => guard.sync(encryption_key),
+            StagedKey::Memory(stage) => stage.sync(encryption_key),
+        }
+    }
+}
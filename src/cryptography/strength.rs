@@ -0,0 +1,357 @@
+// A small zxcvbn-style password strength estimate. It approximates the
+// number of guesses an attacker needs and maps it onto zxcvbn's 0-4 score:
+//
+//   0  < 10^3 guesses   (too guessable)
+//   1  < 10^6           (very guessable)
+//   2  < 10^8           (somewhat guessable)
+//   3  < 10^10          (safely unguessable)
+//   4  >= 10^10         (very unguessable)
+//
+// Like zxcvbn, the password is split into the cheapest sequence of pieces:
+// dictionary words (also capitalised or with leetspeak substitutions), years,
+// digit runs, repeats, sequences like `abcd`/`4321`, keyboard rows, and
+// brute-forced characters for everything else. The guesses for the pieces
+// are multiplied, i.e. their log10 values added.
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "passw0rd", "123456", "12345678", "123456789", "1234567890",
+    "qwerty", "qwertyuiop", "abc123", "111111", "letmein", "welcome",
+    "monkey", "dragon", "iloveyou", "admin", "login", "master", "sunshine",
+    "princess", "football", "baseball", "shadow", "superman", "trustno1",
+    "commune", "mnemonic", "wallet", "secret", "changeme",
+];
+
+// Words that, with a capital letter, a year or a `!` added, make up a large
+// share of leaked passwords.
+const COMMON_WORDS: &[&str] = &[
+    "summer", "winter", "spring", "autumn", "january", "february", "march",
+    "april", "june", "july", "august", "september", "october", "november",
+    "december", "monday", "friday", "sunday", "love", "hello", "freedom",
+    "whatever", "michael", "jordan", "hunter", "ranger", "soccer", "hockey",
+    "killer", "pepper", "ginger", "cheese", "computer", "internet", "starwars",
+    "pokemon", "matrix", "batman", "flower", "orange", "banana", "chocolate",
+    "charlie", "thomas", "jessica", "ashley", "daniel", "andrew", "purple",
+    "silver", "golden", "cookie", "maggie", "liverpool", "chelsea", "mustang",
+    "access", "money", "secure", "default", "guest", "user", "root", "crypto",
+    "bitcoin", "key",
+];
+
+// Longer passwords are only analysed up to here.
+const MAX_ANALYSED: usize = 100;
+
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+// Characters commonly swapped in for letters of a dictionary word.
+const LEET: &[(char, &[char])] = &[
+    ('a', &['@', '4']),
+    ('b', &['8']),
+    ('e', &['3']),
+    ('g', &['9', '6']),
+    ('i', &['1', '!', '|']),
+    ('l', &['1', '|']),
+    ('o', &['0']),
+    ('s', &['$', '5']),
+    ('t', &['7', '+']),
+    ('z', &['2']),
+];
+
+pub struct Estimate {
+    pub score: u8,
+    pub guesses_log10: f64,
+    pub feedback: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    BruteForce,
+    Dictionary,
+    Year,
+    Digits,
+    Repeat,
+    Sequence,
+    Keyboard,
+}
+
+/// A piece of the password from `start` to `end` (in chars) and the log10 of
+/// the guesses needed to find it.
+struct Piece {
+    start: usize,
+    end: usize,
+    guesses_log10: f64,
+    pattern: Pattern,
+}
+
+fn score_for(guesses_log10: f64) -> u8 {
+    match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn pool_size(password: &str) -> f64 {
+    let mut pool = 0.0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26.0;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26.0;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10.0;
+    }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33.0;
+    }
+    if !password.is_ascii() {
+        pool += 100.0;
+    }
+    f64::max(pool, 1.0)
+}
+
+fn leet_matches(c: char, letter: char) -> bool {
+    c == letter
+        || LEET
+            .iter()
+            .any(|(plain, substitutes)| *plain == letter && substitutes.contains(&c))
+}
+
+/// Extra guesses for the capitalisation and substitutions used on a
+/// dictionary word: one bit for a leading or all-caps capital, one per
+/// uppercase letter otherwise, and one per substituted character.
+fn variation_log10(word: &[char], substitutions: usize) -> f64 {
+    let upper = word.iter().filter(|c| c.is_uppercase()).count();
+    let case_bits = if upper == 0 {
+        0
+    } else if upper == word.len() || (upper == 1 && word[0].is_uppercase()) {
+        1
+    } else {
+        upper
+    };
+    (case_bits + substitutions) as f64 * 2f64.log10()
+}
+
+fn dictionary_pieces(chars: &[char], pieces: &mut Vec<Piece>) {
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    if lower.len() != chars.len() {
+        return;
+    }
+    for (list, rank_offset) in [(COMMON_PASSWORDS, 0), (COMMON_WORDS, COMMON_PASSWORDS.len())] {
+        for (rank, word) in list.iter().enumerate() {
+            let word: Vec<char> = word.chars().collect();
+            if word.len() > chars.len() {
+                continue;
+            }
+            for start in 0..=chars.len() - word.len() {
+                let end = start + word.len();
+                let candidate = &lower[start..end];
+                if candidate.iter().zip(&word).all(|(c, letter)| leet_matches(*c, *letter)) {
+                    let substitutions = candidate.iter().zip(&word).filter(|(c, letter)| c != letter).count();
+                    pieces.push(Piece {
+                        start,
+                        end,
+                        guesses_log10: ((rank + rank_offset + 10) as f64).log10()
+                            + variation_log10(&chars[start..end], substitutions),
+                        pattern: Pattern::Dictionary,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn digit_pieces(chars: &[char], pieces: &mut Vec<Piece>) {
+    for start in 0..chars.len() {
+        let run = chars[start..].iter().take_while(|c| c.is_ascii_digit()).count();
+        for len in 2..=run {
+            pieces.push(Piece {
+                start,
+                end: start + len,
+                guesses_log10: len as f64,
+                pattern: Pattern::Digits,
+            });
+        }
+        if run >= 4 {
+            let year: String = chars[start..start + 4].iter().collect();
+            if year.starts_with("19") || year.starts_with("20") {
+                pieces.push(Piece {
+                    start,
+                    end: start + 4,
+                    guesses_log10: 200f64.log10(),
+                    pattern: Pattern::Year,
+                });
+            }
+        }
+    }
+}
+
+/// Runs like `abcd`, `4321` or `aceg` that step through letters or digits.
+fn sequence_pieces(chars: &[char], pieces: &mut Vec<Piece>) {
+    for start in 0..chars.len() {
+        let step = match chars.get(start + 1) {
+            Some(next) => *next as i64 - chars[start] as i64,
+            None => break,
+        };
+        if step == 0 || step.abs() > 2 {
+            continue;
+        }
+        let mut end = start + 2;
+        while end < chars.len() && chars[end] as i64 - chars[end - 1] as i64 == step {
+            end += 1;
+        }
+        for len in 3..=end - start {
+            let first = chars[start];
+            let start_choices: f64 = if first.is_ascii_digit() { 10.0 } else { 26.0 };
+            let direction = if step < 0 { 2.0 } else { 1.0 };
+            pieces.push(Piece {
+                start,
+                end: start + len,
+                guesses_log10: (start_choices * direction * step.abs() as f64 * len as f64).log10(),
+                pattern: Pattern::Sequence,
+            });
+        }
+    }
+}
+
+fn keyboard_pieces(chars: &[char], pieces: &mut Vec<Piece>) {
+    let lower: String = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    if lower.chars().count() != chars.len() {
+        return;
+    }
+    for row in KEYBOARD_ROWS {
+        let reversed: String = row.chars().rev().collect();
+        for (row, direction) in [(*row, 1.0), (reversed.as_str(), 2.0)] {
+            for window in 4..=row.len() {
+                for start in 0..=row.len() - window {
+                    let pattern = &row[start..start + window];
+                    let mut offset = 0;
+                    while let Some(found) = lower[offset..].find(pattern) {
+                        let begin = lower[..offset + found].chars().count();
+                        pieces.push(Piece {
+                            start: begin,
+                            end: begin + window,
+                            guesses_log10: (40.0 * direction * window as f64).log10(),
+                            pattern: Pattern::Keyboard,
+                        });
+                        offset += found + pattern.len();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A block repeated back to back, like `aaaa` or `abcabcabc`, costs the
+/// guesses for the block times the number of repeats.
+fn repeat_pieces(chars: &[char], char_log10: f64, pieces: &mut Vec<Piece>) {
+    for start in 0..chars.len() {
+        for block in 1..=(chars.len() - start) / 2 {
+            let base = &chars[start..start + block];
+            let mut count = 1;
+            while chars[start + count * block..]
+                .get(..block)
+                .is_some_and(|next| next == base)
+            {
+                count += 1;
+            }
+            if count < 2 {
+                continue;
+            }
+            let (base_log10, _) = cheapest(base, char_log10, false);
+            pieces.push(Piece {
+                start,
+                end: start + count * block,
+                guesses_log10: base_log10 + (count as f64).log10(),
+                pattern: Pattern::Repeat,
+            });
+        }
+    }
+}
+
+/// The cheapest way to cover `chars` with pieces, as the log10 of the
+/// guesses and the patterns used. The block of a repeat is estimated without
+/// looking for repeats inside it again.
+fn cheapest(chars: &[char], char_log10: f64, repeats: bool) -> (f64, Vec<Pattern>) {
+    let mut pieces = Vec::new();
+    dictionary_pieces(chars, &mut pieces);
+    digit_pieces(chars, &mut pieces);
+    sequence_pieces(chars, &mut pieces);
+    keyboard_pieces(chars, &mut pieces);
+    if repeats {
+        repeat_pieces(chars, char_log10, &mut pieces);
+    }
+    pieces.sort_by_key(|piece| piece.end);
+
+    // best[i] is the cheapest cover of the first i characters and the piece
+    // it ends with.
+    let mut best: Vec<(f64, Option<usize>)> = vec![(0.0, None); chars.len() + 1];
+    let mut next_piece = 0;
+    for end in 1..=chars.len() {
+        best[end] = (best[end - 1].0 + char_log10, None);
+        while next_piece < pieces.len() && pieces[next_piece].end == end {
+            let piece = &pieces[next_piece];
+            let cost = best[piece.start].0 + piece.guesses_log10;
+            if cost < best[end].0 {
+                best[end] = (cost, Some(next_piece));
+            }
+            next_piece += 1;
+        }
+    }
+
+    let mut patterns = Vec::new();
+    let mut position = chars.len();
+    while position > 0 {
+        match best[position].1 {
+            Some(index) => {
+                patterns.push(pieces[index].pattern);
+                position = pieces[index].start;
+            }
+            None => {
+                patterns.push(Pattern::BruteForce);
+                position -= 1;
+            }
+        }
+    }
+    (best[chars.len()].0, patterns)
+}
+
+pub fn estimate(password: &str) -> Estimate {
+    if password.is_empty() {
+        return Estimate { score: 0, guesses_log10: 0.0, feedback: Some("Use a password") };
+    }
+
+    let lower = password.to_lowercase();
+    let stripped = lower.trim_end_matches(|c: char| c.is_ascii_digit() || c == '!');
+    if COMMON_PASSWORDS.contains(&lower.as_str()) || COMMON_PASSWORDS.contains(&stripped) {
+        return Estimate {
+            score: 0,
+            guesses_log10: 2.0,
+            feedback: Some("This is a very common password"),
+        };
+    }
+
+    // Anything past the first MAX_ANALYSED characters is ignored, which
+    // keeps the search fast and only ever underestimates.
+    let chars: Vec<char> = password.chars().take(MAX_ANALYSED).collect();
+    let (guesses_log10, patterns) = cheapest(&chars, pool_size(password).log10(), true);
+    let score = score_for(guesses_log10);
+
+    let feedback = if score >= 3 {
+        None
+    } else if patterns.iter().any(|p| matches!(p, Pattern::Dictionary | Pattern::Year)) {
+        Some("Avoid common words and years, even with capitals or symbols swapped in")
+    } else if patterns
+        .iter()
+        .any(|p| matches!(p, Pattern::Repeat | Pattern::Sequence | Pattern::Keyboard))
+    {
+        Some("Avoid repeated characters, sequences and keyboard patterns")
+    } else if chars.len() < 12 {
+        Some("Use a longer password or a passphrase of several words")
+    } else {
+        Some("Mix upper and lower case letters, digits and symbols")
+    };
+
+    Estimate { score, guesses_log10, feedback }
+}
@@ -0,0 +1,184 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use anyhow::anyhow;
+use crate::cryptography::encryption::EncryptionKey;
+use crate::cryptography::secret::{SecretBytes, SecretString};
+
+// Derived keys cached in the Linux kernel session keyring, as `user` keys
+// described `cli_wrapper:<name>` holding `<kdf> <salt hex> <key hex>`. The
+// kernel drops them when their timeout expires or the login session ends,
+// and only processes in the session can read them.
+
+const KEY_TYPE: &CStr = c"user";
+const DESCRIPTION_PREFIX: &str = "cli_wrapper:";
+
+// Longest key payload.
+const MAX_PAYLOAD: usize = 512;
+
+// Everything for possessors, only viewing the description for other
+// processes of the same user.
+const PERMISSIONS: libc::c_ulong = 0x3f01_0000;
+
+fn description(key_name: &str) -> anyhow::Result<CString> {
+    CString::new(format!("{}{}", DESCRIPTION_PREFIX, key_name)).map_err(|_| anyhow!("Invalid key name: {}", key_name))
+}
+
+fn keyctl(operation: u32, args: [libc::c_ulong; 4]) -> io::Result<libc::c_long> {
+    // SAFETY: callers pass pointers that are valid for the operation.
+    let result = unsafe { libc::syscall(libc::SYS_keyctl, operation as libc::c_int, args[0], args[1], args[2], args[3]) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+/// The session keyring, or the user's default session keyring when the
+/// login did not set one up. Asking `add_key` for the session keyring
+/// instead would create one that only lives as long as this process.
+fn session_keyring() -> io::Result<libc::c_long> {
+    keyctl(libc::KEYCTL_GET_KEYRING_ID, [libc::KEY_SPEC_SESSION_KEYRING as libc::c_ulong, 0, 0, 0])
+}
+
+fn search(key_name: &str) -> anyhow::Result<Option<libc::c_long>> {
+    let description = description(key_name)?;
+    let result = keyctl(
+        libc::KEYCTL_SEARCH,
+        [
+            libc::KEY_SPEC_SESSION_KEYRING as libc::c_ulong,
+            KEY_TYPE.as_ptr() as libc::c_ulong,
+            description.as_ptr() as libc::c_ulong,
+            0,
+        ],
+    );
+    match result {
+        Ok(id) => Ok(Some(id)),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOKEY | libc::EKEYEXPIRED | libc::EKEYREVOKED)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the payload of key `id` into `buffer`, returning its full length.
+fn read(id: libc::c_long, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let length = keyctl(
+        libc::KEYCTL_READ,
+        [id as libc::c_ulong, buffer.as_mut_ptr() as libc::c_ulong, buffer.capacity() as libc::c_ulong, 0],
+    )? as usize;
+    // SAFETY: the kernel wrote min(length, capacity) bytes.
+    unsafe { buffer.set_len(length.min(buffer.capacity())) };
+    Ok(length)
+}
+
+/// Stores `key` in the session keyring for `timeout_secs` seconds (0 keeps
+/// it until forgotten or the session ends), replacing an older copy.
+pub fn store(key_name: &str, key: &EncryptionKey, timeout_secs: u64) -> anyhow::Result<()> {
+    let description = description(key_name)?;
+    let keyring = session_keyring()?;
+    let payload = SecretString::build(MAX_PAYLOAD, |payload| key.push_fields(payload));
+
+    // SAFETY: the strings are NUL-terminated and payload is valid for its length.
+    let id = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            KEY_TYPE.as_ptr(),
+            description.as_ptr(),
+            payload.expose().as_ptr(),
+            payload.expose().len(),
+            keyring as libc::c_int,
+        )
+    };
+    if id < 0 {
+        return Err(anyhow!("Failed to add the key to the session keyring: {}", io::Error::last_os_error()));
+    }
+    keyctl(libc::KEYCTL_SETPERM, [id as libc::c_ulong, PERMISSIONS, 0, 0])?;
+    keyctl(libc::KEYCTL_SET_TIMEOUT, [id as libc::c_ulong, timeout_secs as libc::c_ulong, 0, 0])?;
+    Ok(())
+}
+
+/// The key cached for `key_name` in the session keyring, if there is one.
+pub fn load(key_name: &str) -> Option<EncryptionKey> {
+    let id = search(key_name).ok()??;
+    let mut length = 0;
+    let payload = SecretBytes::fill(MAX_PAYLOAD, |buffer| read(id, buffer).map(|read| length = read)).ok()?;
+    if length > MAX_PAYLOAD {
+        return None;
+    }
+    let fields: Vec<&str> = std::str::from_utf8(payload.expose()).ok()?.split(' ').collect();
+    EncryptionKey::from_fields(&fields)
+}
+
+fn revoke(id: libc::c_long) -> io::Result<()> {
+    keyctl(libc::KEYCTL_REVOKE, [id as libc::c_ulong, 0, 0, 0])?;
+    // Revoked keys linger until the garbage collector runs; unlinking takes
+    // it out of the keyring listing right away.
+    let _ = keyctl(libc::KEYCTL_UNLINK, [id as libc::c_ulong, libc::KEY_SPEC_SESSION_KEYRING as libc::c_ulong, 0, 0]);
+    Ok(())
+}
+
+/// Revokes the cached key for `key_name`. Returns whether there was one.
+pub fn forget(key_name: &str) -> anyhow::Result<bool> {
+    match search(key_name)? {
+        Some(id) => {
+            revoke(id)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// `type;uid;gid;perm;description` of key `id`.
+fn describe(id: libc::c_long) -> io::Result<String> {
+    let mut buffer = Vec::with_capacity(256);
+    let length = read_description(id, &mut buffer)?;
+    if length > buffer.capacity() {
+        buffer = Vec::with_capacity(length);
+        read_description(id, &mut buffer)?;
+    }
+    // The description is NUL-terminated.
+    buffer.pop();
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+fn read_description(id: libc::c_long, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let length = keyctl(
+        libc::KEYCTL_DESCRIBE,
+        [id as libc::c_ulong, buffer.as_mut_ptr() as libc::c_ulong, buffer.capacity() as libc::c_ulong, 0],
+    )? as usize;
+    // SAFETY: the kernel wrote min(length, capacity) bytes.
+    unsafe { buffer.set_len(length.min(buffer.capacity())) };
+    Ok(length)
+}
+
+/// Names of the keys cached in the session keyring.
+fn cached_keys() -> anyhow::Result<Vec<(String, libc::c_long)>> {
+    let session = libc::KEY_SPEC_SESSION_KEYRING as libc::c_long;
+    let mut ids: Vec<u8> = Vec::with_capacity(1024);
+    let length = read(session, &mut ids)?;
+    if length > ids.capacity() {
+        ids = Vec::with_capacity(length);
+        read(session, &mut ids)?;
+    }
+
+    let mut keys = Vec::new();
+    for id in ids.chunks_exact(4) {
+        let id = i32::from_ne_bytes([id[0], id[1], id[2], id[3]]) as libc::c_long;
+        let Ok(description) = describe(id) else { continue };
+        let mut fields = description.splitn(5, ';');
+        if fields.next() != Some("user") {
+            continue;
+        }
+        if let Some(name) = fields.nth(3).and_then(|description| description.strip_prefix(DESCRIPTION_PREFIX)) {
+            keys.push((name.to_string(), id));
+        }
+    }
+    Ok(keys)
+}
+
+/// Revokes every key cached in the session keyring and returns their names.
+pub fn forget_all() -> anyhow::Result<Vec<String>> {
+    let mut forgotten = Vec::new();
+    for (name, id) in cached_keys()? {
+        revoke(id)?;
+        forgotten.push(name);
+    }
+    Ok(forgotten)
+}
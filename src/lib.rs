@@ -1,2 +1,8 @@
+pub mod agent;
+pub mod config;
 pub mod cryptography;
+pub mod keyring;
+pub mod repl;
+pub mod session;
+pub mod shell_words;
 pub mod wrapper;
\ No newline at end of file
@@ -1,144 +1,602 @@
 mod wrapper;
 mod cryptography;
+mod config;
+mod agent;
+mod keyring;
+mod session;
+mod repl;
+mod shell_words;
 
-use std::env;
-use std::io::{self, BufRead};
-use cryptography::encryption::get_encryption_key;
-use crate::cryptography::{encryption, input, cleanup};
-use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+use std::time::Duration;
+use cryptography::encryption::{get_encryption_key, EncryptionKey};
+use crate::cryptography::{derive, encryption, input, cleanup, hardening, permissions};
+use crate::cryptography::input::PasswordSource;
+use crate::cryptography::secret::SecretString;
+use crate::cryptography::staging;
+use crate::repl::{History, Input, LineReader, ShellHelper};
+use crate::session::Session;
+use crate::config::{Config, StagingMode};
+use anyhow::Result;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 
+/// Keeps commune keys encrypted at rest and decrypts them only while `comx` needs them.
+#[derive(Parser)]
+#[command(name = "cli-wrapper", version)]
+struct Cli {
+    /// Decrypt keys into a private tmpfs directory instead of ~/.commune/key
+    #[arg(long, global = true)]
+    in_memory: bool,
 
+    /// Disable core dumps and ptrace access and lock secrets into RAM
+    #[arg(long, global = true)]
+    harden: bool,
 
-fn execute_command(key_name: &str, encryption_key: &[u8], args: &[String]) -> Result<()> {
-    // println!("Debug: Starting execute_command for key '{}'", key_name);
-    // println!("Debug: Command args: {:?}", args);
-    
-    match encryption::decrypt_key_file(key_name, encryption_key) {
-        Ok(_) => println!("Key file decrypted successfully"),
-        Err(e) => {
-            println!("Failed to decrypt key file: {:?}", e);
-            return Err(e.into());
+    /// Ask for passwords in a pinentry dialog
+    #[arg(long, global = true)]
+    pinentry: bool,
+
+    /// Cache unlocked keys in the kernel session keyring
+    #[arg(long, global = true)]
+    keyring: bool,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+
+    /// Without a command, starts an interactive shell for a key
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Non-interactive password input, one password per line. Each of these
+/// has risks, see the README.
+#[derive(Args)]
+#[group(multiple = false)]
+struct PasswordArgs {
+    /// Read passwords from an inherited file descriptor
+    #[arg(long, value_name = "N", global = true)]
+    password_fd: Option<i32>,
+
+    /// Read passwords from a file that only you can read
+    #[arg(long, value_name = "PATH", global = true)]
+    password_file: Option<PathBuf>,
+
+    /// Read passwords from standard input until end of file
+    #[arg(long, global = true)]
+    password_stdin: bool,
+
+    /// Read the password from an environment variable
+    #[arg(long, value_name = "NAME", global = true)]
+    password_env: Option<String>,
+}
+
+impl PasswordArgs {
+    fn source(&self) -> Option<PasswordSource> {
+        if let Some(fd) = self.password_fd {
+            Some(PasswordSource::Fd(fd))
+        } else if let Some(path) = &self.password_file {
+            Some(PasswordSource::File(path.clone()))
+        } else if self.password_stdin {
+            Some(PasswordSource::Stdin)
+        } else {
+            self.password_env.clone().map(PasswordSource::Env)
         }
     }
-    
-    let _guard = cleanup::KeyFileGuard {
-        key_name: key_name.to_string(),
-    };
-    
-    // Display the command being executed
-    println!("Executing command: comx {}", args.join(" "));
-    
-    match wrapper::execute_cli_command(key_name, args) {
-        Ok(output) => {
-            println!("Command executed successfully");
-            println!("Output:");
-            println!("{:?}", output);
-        },
-        Err(e) => {
-            println!("Failed to execute CLI command: {:?}", e);
-            return Err(e);
+}
+
+fn parse_key_name(key_name: &str) -> Result<String, String> {
+    encryption::check_key_name(key_name).map_err(|e| e.to_string())?;
+    Ok(key_name.to_string())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decrypt a key to ~/.commune/key and leave it there
+    Unlock {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+    },
+    /// Encrypt a key and remove its plaintext file
+    Lock {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+    },
+    /// Run a single comx command with the key unlocked
+    #[command(after_help = "Example: cli-wrapper run mykey -- balance show mykey")]
+    Run {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+        /// Arguments passed to comx, after an optional `--`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Read comx commands from the terminal with the key unlocked
+    Shell {
+        #[arg(value_parser = parse_key_name)]
+        key: Option<String>,
+        /// Lock the key after this many seconds without input, 0 for never [default: session.idle_timeout_secs]
+        #[arg(long, value_name = "SECS")]
+        idle_timeout: Option<u64>,
+        /// Lock the key this many seconds after unlocking it, 0 for never [default: session.max_lifetime_secs]
+        #[arg(long, value_name = "SECS")]
+        max_lifetime: Option<u64>,
+    },
+    /// Recreate a key from its mnemonic and encrypt it
+    Regen {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+    },
+    /// List the keys in the vault
+    List,
+    /// Show what is on disk for one key, or for all of them
+    Status {
+        #[arg(value_parser = parse_key_name)]
+        key: Option<String>,
+    },
+    /// Re-encrypt every key under a new password
+    Passwd,
+    /// Pick Argon2id parameters for this machine and save them to the config
+    Calibrate {
+        /// Unlock time to aim for, in milliseconds
+        #[arg(long)]
+        target_ms: Option<u64>,
+    },
+    /// Re-encrypt a key with its own salt and the configured Argon2id parameters
+    Migrate {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+    },
+    /// Manage the background agent that keeps unlocked keys in memory
+    Agent {
+        #[command(subcommand)]
+        command: AgentCommand,
+    },
+    /// Revoke a key cached in the kernel keyring, or all of them
+    Forget {
+        #[arg(value_parser = parse_key_name)]
+        key: Option<String>,
+    },
+    /// Keep one copy of a key whose plaintext and encrypted files differ
+    Reconcile {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+        #[arg(value_enum)]
+        keep: Keep,
+    },
+}
+
+#[derive(Subcommand)]
+enum AgentCommand {
+    /// Start the agent in the background
+    Start {
+        /// Stay in the foreground instead
+        #[arg(long)]
+        foreground: bool,
+    },
+    /// Stop the agent, forgetting all keys
+    Stop,
+    /// Ask for a key's password and hand the derived key to the agent
+    Unlock {
+        #[arg(value_parser = parse_key_name)]
+        key: String,
+        /// Seconds until the agent forgets the key, 0 for never [default: agent.ttl_secs]
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// Make the agent forget one key, or all of them
+    Lock {
+        #[arg(value_parser = parse_key_name)]
+        key: Option<String>,
+    },
+    /// List the keys the agent holds
+    Status,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Keep {
+    KeepPlaintext,
+    KeepEncrypted,
+}
+
+impl From<Keep> for encryption::Reconcile {
+    fn from(keep: Keep) -> Self {
+        match keep {
+            Keep::KeepPlaintext => encryption::Reconcile::KeepPlaintext,
+            Keep::KeepEncrypted => encryption::Reconcile::KeepEncrypted,
         }
     }
-    
-    match encryption::encrypt_key_file(key_name, encryption_key) {
-        Ok(_) => println!("Key file encrypted successfully"),
-        Err(e) => {
-            println!("Failed to encrypt key file: {:?}", e);
-            return Err(e.into());
+}
+
+/// A key held by the agent or the kernel keyring that still opens the key
+/// file. Cached keys no longer match once the password or salt changed.
+fn cached_key(key_name: &str, config: &Config) -> Option<EncryptionKey> {
+    let matches = |encryption_key: &EncryptionKey| encryption::verify_encryption_key(key_name, encryption_key).is_ok();
+    if let Some(encryption_key) = agent::cached_key(key_name).filter(matches) {
+        return Some(encryption_key);
+    }
+    if config.keyring.enabled {
+        return keyring::load(key_name).filter(matches);
+    }
+    None
+}
+
+/// Takes the key from a cache or prompts for its password, and cleans up
+/// any plaintext a previous, crashed run left behind for it.
+fn unlock(key_name: &str, config: &Config) -> Result<EncryptionKey> {
+    let encryption_key = match cached_key(key_name, config) {
+        Some(encryption_key) => encryption_key,
+        None => prompt_key(key_name, config)?,
+    };
+    cleanup::recover_stale_key(key_name, &encryption_key)?;
+    Ok(encryption_key)
+}
+
+/// Asks for the key's password, bypassing the agent and the keyring, and
+/// caches the result in the keyring if it is enabled.
+fn prompt_key(key_name: &str, config: &Config) -> Result<EncryptionKey> {
+    let encryption_key = get_encryption_key(key_name, config)?;
+    if config.keyring.enabled {
+        if let Err(e) = keyring::store(key_name, &encryption_key, config.keyring.timeout_secs) {
+            println!("Warning: could not cache the key in the kernel keyring: {}", e);
         }
     }
-    
+    Ok(encryption_key)
+}
+
+fn calibrate(config: &Config, target_ms: Option<u64>) -> Result<()> {
+    let mut kdf_config = config.kdf.clone();
+    if let Some(target_ms) = target_ms {
+        kdf_config.target_ms = target_ms;
+    }
+    let target = Duration::from_millis(kdf_config.target_ms);
+    println!("Calibrating Argon2id for a {} ms unlock time...", kdf_config.target_ms);
+
+    let (kdf, measured) = derive::calibrate_argon2id(target, kdf_config.memory_kib, kdf_config.parallelism)?;
+    kdf_config.set_kdf(&kdf);
+    Config::save_kdf(&kdf_config)?;
+
+    println!(
+        "Selected memory = {} KiB, iterations = {}, parallelism = {} ({} ms on this machine)",
+        kdf_config.memory_kib,
+        kdf_config.iterations,
+        kdf_config.parallelism,
+        measured.as_millis()
+    );
+    println!("These parameters will be used for newly encrypted keys.");
+    Ok(())
+}
+
+/// Runs a single comx command with the key staged around it.
+fn execute_command(key_name: &str, encryption_key: EncryptionKey, staging: StagingMode, args: &[String]) -> Result<()> {
+    let mut session = Session::new(key_name, staging);
+    session.unlock(encryption_key)?;
+    session.run(args)?;
+    println!("Command executed successfully");
+    session.lock()?;
+    Ok(())
+}
+
+/// Regenerates a key in a staging directory in memory, the way `run` stages
+/// keys with `--in-memory`, so the plaintext `comx` writes never reaches the
+/// disk. Only the encrypted file lands in the key directory.
+fn regen_in_memory(key_name: &str, mnemonic: &SecretString, config: &Config) -> Result<()> {
+    let mut stage = staging::MemoryStage::empty(key_name)?;
+    wrapper::regen_key_command(key_name, mnemonic, Some(stage.home()))?;
+    let encryption_key = encryption::get_encryption_key_unchecked(key_name, config)?;
+    if !stage.sync(&encryption_key)? {
+        return Err(anyhow::anyhow!("comx did not write key '{}'", key_name));
+    }
     Ok(())
 }
 
-fn interactive_mode(key_name: &str, encryption_key: &[u8]) -> Result<()> {
-    // println!("Debug: Entered interactive_mode with key: {}", key_name);
-    println!("Listening for commands. Type 'comx' followed by your command, or 'exit' to quit.");
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let input = line?;
-        let parts: Vec<String> = input.split_whitespace().map(String::from).collect();
+/// Unlocks `session` again inside the shell. After a session limit locked
+/// it (`expired`), the password is always asked for, so a cached key cannot
+/// undo the limit. A failure, e.g. a mistyped password, is printed and
+/// leaves the session locked instead of ending the shell. Returns whether
+/// the key is unlocked.
+fn unlock_session(session: &mut Session, key_name: &str, config: &Config, expired: bool) -> bool {
+    let encryption_key = if expired { prompt_key(key_name, config) } else { unlock(key_name, config) };
+    let result = encryption_key.and_then(|encryption_key| Ok(session.unlock(encryption_key)?));
+    if let Err(e) = &result {
+        println!("Failed to unlock key '{}': {}", key_name, e);
+    }
+    result.is_ok()
+}
+
+/// Reads commands from the terminal. The key stays staged from `unlock`
+/// until `lock`, `exit` or a session limit; a command given while it is
+/// locked unlocks it again first.
+fn interactive_mode(key_name: &str, encryption_key: EncryptionKey, config: &Config) -> Result<()> {
+    let mut session = Session::new(key_name, config.staging.mode);
+    session.set_limits(config.session.idle_timeout(), config.session.max_lifetime());
+    session.unlock(encryption_key)?;
+    let mut history = History::load(key_name, &config.history, session.encryption_key());
+    let helper = ShellHelper::new(all_keys()?);
+    let mut reader = LineReader::spawn(format!("{}> ", key_name), &history, helper)?;
+    let mut expired = false;
+    println!("Listening for commands. Type 'comx' followed by your command, 'lock' or 'unlock' to lock or unlock the key, or 'exit' to quit. Tab completes commands and key names.");
+    loop {
+        let input = match reader.next_line(session.time_left())? {
+            Input::Line(input) => input,
+            Input::TimedOut => {
+                reader.print_above(|| {
+                    println!("Session limit reached, locking key '{}'. The next command asks for the password again.", key_name);
+                    session.expire();
+                });
+                expired = true;
+                continue;
+            }
+            Input::Closed => break,
+        };
+        session.touch();
+        let parts = match shell_words::split(&input) {
+            Ok(parts) => parts,
+            Err(e) => {
+                println!("Invalid command: {}", e);
+                println!("  {}", input);
+                println!("  {}^", " ".repeat(e.column()));
+                history.add(&input, session.encryption_key());
+                continue;
+            }
+        };
         
         // println!("Debug: Received command: {:?}", parts);
         
-        if parts.get(0) == Some(&"comx".to_string()) && parts.len() > 1 {
-            let args = &parts[1..];
-            // println!("Debug: Executing command for key: {} with args: {:?}", key_name, args);
-            match execute_command(key_name, encryption_key, args) {
-                Ok(_) => println!("Command executed successfully"),
-                Err(e) => println!("Error executing command: {:?}", e),
-            }
-        } else if input.trim().to_lowercase() == "exit" {
-            break;
-        } else {
-            println!("Invalid command. Use 'comx' followed by your command, or type 'exit' to quit.");
+        match parts.first().map(String::as_str) {
+            Some("comx") if parts.len() > 1 => {
+                if session.is_unlocked() || unlock_session(&mut session, key_name, config, expired) {
+                    match session.run(&parts[1..]) {
+                        Ok(_) => println!("Command executed successfully"),
+                        Err(e) => println!("Error executing command: {:?}", e),
+                    }
+                }
+            }
+            Some("unlock") if parts.len() == 1 => {
+                if session.is_unlocked() {
+                    println!("Key '{}' is already unlocked", key_name);
+                } else {
+                    unlock_session(&mut session, key_name, config, expired);
+                }
+            }
+            Some("lock") if parts.len() == 1 => {
+                if let Err(e) = session.lock() {
+                    println!("Failed to lock key: {:?}", e);
+                }
+            }
+            None => {}
+            Some(command) if parts.len() == 1 && command.eq_ignore_ascii_case("exit") => break,
+            _ => println!("Invalid command. Use 'comx' followed by your command, 'lock', 'unlock', or type 'exit' to quit."),
         }
+        history.add(&input, session.encryption_key());
     }
+    session.lock()?;
     Ok(())
 }
 
-pub fn main() -> Result<()> {
-    // encryption::test_key_derivation();
-    let args: Vec<String> = env::args().collect();
-    
-    // println!("Debug: Command line args: {:?}", args);
-
-    // Parse arguments and check for '--regen_key' flag
-    let mut args_iter = args.iter().skip(1); // Skip the program name
-    let mut regen_key = false;
-    let mut key_name = String::new();
-    let mut command_args = Vec::new();
-
-    while let Some(arg) = args_iter.next() {
-        match arg.as_str() {
-            "--regen_key" => {
-                regen_key = true;
-            },
-            other => {
-                if key_name.is_empty() {
-                    key_name = other.to_string();
-                } else {
-                    command_args.push(other.to_string());
+/// Warns about other keys a crashed run left decrypted and suggests
+/// migrating `key_name` if it uses an old format.
+fn print_key_notes(key_name: &str) -> Result<()> {
+    for stale in cleanup::stale_keys()? {
+        if stale != key_name {
+            println!(
+                "Warning: plaintext key '{}' was left decrypted by a previous run. Run `cli-wrapper lock {}` to lock it.",
+                stale, stale
+            );
+        }
+    }
+
+    if encryption::needs_migration(key_name)? {
+        println!(
+            "Note: key '{}' uses the shared salt or an old key derivation function. Run `cli-wrapper migrate {}` to upgrade it.",
+            key_name, key_name
+        );
+    }
+    Ok(())
+}
+
+fn agent_command(command: AgentCommand, config: &Config) -> Result<()> {
+    match command {
+        AgentCommand::Start { foreground: false } => agent::spawn()?,
+        AgentCommand::Start { foreground: true } => {
+            // The agent holds keys for a long time, so always harden it.
+            if !config.hardening.enabled {
+                for failure in hardening::harden_process() {
+                    println!("Warning: hardening failed, {}", failure);
+                }
+            }
+            agent::serve()?
+        }
+        AgentCommand::Stop => {
+            agent::stop()?;
+            println!("Agent stopped");
+        }
+        AgentCommand::Unlock { key, ttl } => {
+            let encryption_key = get_encryption_key(&key, config)?;
+            let ttl = ttl.unwrap_or(config.agent.ttl_secs);
+            agent::add_key(&key, &encryption_key, ttl)?;
+            match ttl {
+                0 => println!("Key '{}' added to the agent until it is locked", key),
+                ttl => println!("Key '{}' added to the agent for {}s", key, ttl),
+            }
+        }
+        AgentCommand::Lock { key } => {
+            agent::lock(key.as_deref())?;
+            println!("Agent locked");
+        }
+        AgentCommand::Status => {
+            let keys = agent::status()?;
+            println!("Agent running on {}", agent::socket_path().display());
+            if keys.is_empty() {
+                println!("No keys unlocked");
+            }
+            for (key_name, left) in keys {
+                match left {
+                    0 => println!("{}: unlocked until locked", key_name),
+                    left => println!("{}: unlocked for {}s", key_name, left),
                 }
             }
         }
     }
+    Ok(())
+}
 
-    if key_name.is_empty() {
-        key_name = input::get_key_name()?;
+fn print_status(key_name: &str) -> Result<()> {
+    let status = encryption::key_status(key_name)?;
+    let state = match (status.encrypted, status.plaintext) {
+        (true, false) => "locked",
+        (true, true) if status.staged => "unlocked by cli-wrapper",
+        (true, true) => "unlocked",
+        (false, true) => "not encrypted",
+        (false, false) => "missing",
+    };
+    println!("{}: {}", key_name, state);
+    if let Some(kdf) = status.kdf {
+        println!("  key derivation: {:?}", kdf);
+    }
+    if status.needs_migration {
+        println!("  needs migration: run `cli-wrapper migrate {}`", key_name);
     }
+    Ok(())
+}
 
-    if regen_key {
-        // Prompt the user to securely input their mnemonic
-        let mnemonic = input::get_mnemonic()?;
+/// Names of every key with an encrypted or a plaintext file.
+fn all_keys() -> Result<Vec<String>> {
+    let mut keys = encryption::list_encrypted_keys()?;
+    keys.extend(encryption::list_plaintext_keys()?);
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+pub fn main() -> Result<()> {
+    // encryption::test_key_derivation();
+    let cli = Cli::parse();
+    // The shell reads its commands from standard input too.
+    if cli.password.password_stdin && matches!(cli.command, None | Some(Command::Shell { .. })) {
+        Cli::command()
+            .error(ErrorKind::ArgumentConflict, "--password-stdin cannot be used with the interactive shell")
+            .exit();
+    }
 
-        // Execute the regeneration command
-        wrapper::regen_key_command(&key_name, &mnemonic)?;
+    let mut config = Config::load()?;
+    if cli.in_memory {
+        config.staging.mode = StagingMode::Memory;
+    }
+    if cli.harden {
+        config.hardening.enabled = true;
+    }
+    if config.hardening.enabled {
+        for failure in hardening::harden_process() {
+            println!("Warning: hardening failed, {}", failure);
+        }
+    }
+    if cli.pinentry {
+        config.pinentry.enabled = true;
+    }
+    if cli.keyring {
+        config.keyring.enabled = true;
     }
+    if config.pinentry.enabled {
+        input::use_pinentry(&config.pinentry.program);
+    }
+    if let Some(source) = cli.password.source() {
+        input::use_password_source(&source)?;
+    }
+    cleanup::install_handlers()?;
+    permissions::check_vault(&config.permissions)?;
+    cleanup::remove_stale_temp_files()?;
+    encryption::recover_password_change()?;
+    staging::remove_stale_staging_dirs()?;
 
-    // Continue with normal execution
-    match command_args.get(0).map(String::as_str) {
-        Some("decrypt") => {
-            // println!("Debug: Decrypting key: {}", key_name);
-            let encryption_key = get_encryption_key()?;
-            encryption::decrypt_key_file(&key_name, &encryption_key)?
-        },
-        Some("encrypt") => {
-            // println!("Debug: Encrypting key: {}", key_name);
-            let encryption_key = get_encryption_key()?;
-            encryption::encrypt_key_file(&key_name, &encryption_key)?;
+    match cli.command {
+        None => {
+            let key_name = input::get_key_name()?;
+            print_key_notes(&key_name)?;
+            let encryption_key = unlock(&key_name, &config)?;
+            interactive_mode(&key_name, encryption_key, &config)?;
+        }
+        Some(Command::Shell { key, idle_timeout, max_lifetime }) => {
+            if let Some(idle_timeout) = idle_timeout {
+                config.session.idle_timeout_secs = idle_timeout;
+            }
+            if let Some(max_lifetime) = max_lifetime {
+                config.session.max_lifetime_secs = max_lifetime;
+            }
+            let key_name = match key {
+                Some(key_name) => key_name,
+                None => input::get_key_name()?,
+            };
+            print_key_notes(&key_name)?;
+            let encryption_key = unlock(&key_name, &config)?;
+            interactive_mode(&key_name, encryption_key, &config)?;
+        }
+        Some(Command::Run { key, args }) => {
+            print_key_notes(&key)?;
+            let encryption_key = unlock(&key, &config)?;
+            execute_command(&key, encryption_key, config.staging.mode, &args)?;
+        }
+        Some(Command::Unlock { key }) => {
+            print_key_notes(&key)?;
+            let encryption_key = unlock(&key, &config)?;
+            encryption::decrypt_key_file(&key, &encryption_key)?;
+        }
+        Some(Command::Lock { key }) => {
+            let encryption_key = unlock(&key, &config)?;
+            encryption::encrypt_key_file(&key, &encryption_key)?;
+        }
+        Some(Command::Regen { key }) => {
+            // Prompt the user to securely input their mnemonic
+            let mnemonic = input::get_mnemonic()?;
+
+            match config.staging.mode {
+                StagingMode::Disk => {
+                    wrapper::regen_key_command(&key, &mnemonic, None)?;
+                    let encryption_key = get_encryption_key(&key, &config)?;
+                    encryption::encrypt_key_file(&key, &encryption_key)?;
+                }
+                StagingMode::Memory => regen_in_memory(&key, &mnemonic, &config)?,
+            }
+        }
+        Some(Command::List) => {
+            let keys = all_keys()?;
+            if keys.is_empty() {
+                println!("No keys found");
+            }
+            for key_name in keys {
+                println!("{}", key_name);
+            }
+        }
+        Some(Command::Status { key }) => {
+            let keys = match key {
+                Some(key_name) => vec![key_name],
+                None => all_keys()?,
+            };
+            for key_name in keys {
+                print_status(&key_name)?;
+            }
+        }
+        Some(Command::Passwd) => encryption::change_password(&config)?,
+        Some(Command::Calibrate { target_ms }) => calibrate(&config, target_ms)?,
+        Some(Command::Migrate { key }) => encryption::migrate_key_file(&key, &config)?,
+        Some(Command::Agent { command }) => agent_command(command, &config)?,
+        Some(Command::Forget { key: Some(key) }) => match keyring::forget(&key)? {
+            true => println!("Key '{}' removed from the kernel keyring", key),
+            false => println!("Key '{}' is not in the kernel keyring", key),
         },
-        _ => {
-            if command_args.is_empty() {
-                // println!("Debug: Entering interactive mode with key: {}", key_name);
-                let encryption_key = get_encryption_key()?;
-                interactive_mode(&key_name, &encryption_key)?;
-            } else {
-                // println!("Debug: Executing command for key: {}", key_name);
-                let encryption_key = get_encryption_key()?;
-                execute_command(&key_name, &encryption_key, &command_args)?;
+        Some(Command::Forget { key: None }) => {
+            let forgotten = keyring::forget_all()?;
+            if forgotten.is_empty() {
+                println!("No keys in the kernel keyring");
+            }
+            for key_name in forgotten {
+                println!("Key '{}' removed from the kernel keyring", key_name);
             }
         }
+        Some(Command::Reconcile { key, keep }) => {
+            let encryption_key = unlock(&key, &config)?;
+            encryption::reconcile_key_file(&key, &encryption_key, keep.into())?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,309 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use dirs::home_dir;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use crate::config::{HistoryConfig, HistoryMode};
+use crate::cryptography::atomic::write_atomic;
+use crate::cryptography::encryption::{self, EncryptionKey};
+use crate::cryptography::permissions;
+use crate::shell_words;
+
+// Lines for the interactive shell are read with a line editor on a separate
+// thread, so that waiting for input can time out. The thread only reads when
+// asked to: password prompts use the same terminal, and a read left pending
+// during a prompt would swallow the password.
+
+/// Commands of the shell itself.
+const SHELL_COMMANDS: &[&str] = &["comx", "exit", "lock", "unlock"];
+
+/// The `comx` command groups and their subcommands, for completion.
+const COMX_COMMANDS: &[(&str, &[&str])] = &[
+    (
+        "balance",
+        &[
+            "all-balance", "free-balance", "get-staked", "run-faucet", "show", "stake", "staked-balance",
+            "transfer", "transfer-dao-funds", "transfer-stake", "unstake",
+        ],
+    ),
+    (
+        "key",
+        &[
+            "balances", "create", "list", "power-delegation", "regen", "save", "show", "stakedbalance",
+            "total-balance", "total-free-balance", "total-staked-balance",
+        ],
+    ),
+    ("misc", &["apr", "circulating-supply"]),
+    ("module", &["deregister", "info", "inventory", "register", "serve", "update"]),
+    (
+        "network",
+        &[
+            "add-custom-proposal", "last-block", "list-proposals", "params", "propose-globally",
+            "registration-burn", "unvote-proposal", "vote-proposal",
+        ],
+    ),
+    (
+        "subnet",
+        &[
+            "add-custom-subnet-proposal", "distribution", "info", "legit-whitelist", "list", "propose-on-subnet",
+            "register", "submit-general-subnet-application", "update",
+        ],
+    ),
+];
+
+/// Options `comx` accepts before the command group.
+const COMX_OPTIONS: &[&str] = &["--color", "--help", "--json", "--no-color", "--testnet", "--version", "--yes-to-all"];
+
+/// Completes shell commands, `comx` command groups, subcommands and options,
+/// and key names as arguments.
+pub struct ShellHelper {
+    key_names: Vec<String>,
+}
+
+impl ShellHelper {
+    pub fn new(key_names: Vec<String>) -> ShellHelper {
+        ShellHelper { key_names }
+    }
+
+    fn candidates(&self, words: &[&str], word: &str) -> Vec<String> {
+        let options = word.starts_with('-');
+        let candidates: Vec<&str> = match words {
+            [] => SHELL_COMMANDS.to_vec(),
+            ["comx", rest @ ..] => {
+                let rest: Vec<&str> = rest.iter().copied().filter(|word| !word.starts_with('-')).collect();
+                match rest.as_slice() {
+                    [] if options => COMX_OPTIONS.to_vec(),
+                    [] => COMX_COMMANDS.iter().map(|(group, _)| *group).collect(),
+                    _ if options => vec!["--help"],
+                    [group] => COMX_COMMANDS
+                        .iter()
+                        .find(|(name, _)| name == group)
+                        .map_or_else(Vec::new, |(_, subcommands)| subcommands.to_vec()),
+                    _ => self.key_names.iter().map(String::as_str).collect(),
+                }
+            }
+            _ => Vec::new(),
+        };
+        candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|(_, c)| shell_words::is_separator(*c))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let words: Vec<&str> = before[..start]
+            .split(shell_words::is_separator)
+            .filter(|word| !word.is_empty())
+            .collect();
+        let pairs = self
+            .candidates(&words, &before[start..])
+            .into_iter()
+            .map(|candidate| Pair { display: candidate.clone(), replacement: format!("{} ", candidate) })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// What waiting for the next line produced.
+pub enum Input {
+    Line(String),
+    TimedOut,
+    Closed,
+}
+
+pub struct LineReader {
+    prompt: String,
+    requests: Sender<()>,
+    lines: Receiver<io::Result<String>>,
+    pending: bool,
+}
+
+impl LineReader {
+    /// Starts the reader thread with an editor that shows `prompt`, starts
+    /// out with the entries of `history` and completes with `helper`.
+    pub fn spawn(prompt: String, history: &History, helper: ShellHelper) -> anyhow::Result<LineReader> {
+        let config = rustyline::Config::builder()
+            .max_history_size(history.max_entries.max(1))?
+            .history_ignore_space(true)
+            .build();
+        let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::with_config(config)?;
+        editor.set_helper(Some(helper));
+        for entry in &history.entries {
+            editor.add_history_entry(entry.as_str())?;
+        }
+
+        let (requests, requested) = mpsc::channel::<()>();
+        let (sender, lines) = mpsc::channel();
+        let thread_prompt = prompt.clone();
+        thread::spawn(move || {
+            while requested.recv().is_ok() {
+                let result = match editor.readline(&thread_prompt) {
+                    Ok(line) => {
+                        let _ = editor.add_history_entry(line.as_str());
+                        Ok(line)
+                    }
+                    // Ctrl-C discards the line being edited.
+                    Err(ReadlineError::Interrupted) => Ok(String::new()),
+                    Err(ReadlineError::Eof) => break,
+                    Err(e) => Err(io::Error::other(e)),
+                };
+                if sender.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(LineReader { prompt, requests, lines, pending: false })
+    }
+
+    /// Runs `print`, which writes to the terminal, while a line may be being
+    /// edited: the output goes below the prompt, which is then shown again.
+    pub fn print_above<F: FnOnce()>(&self, print: F) {
+        if self.pending {
+            println!();
+        }
+        print();
+        if self.pending {
+            print!("{}", self.prompt);
+            let _ = io::stdout().flush();
+        }
+    }
+
+    /// Waits for the next line, for at most `timeout` if one is given. A read
+    /// that timed out stays pending and delivers its line on the next call.
+    pub fn next_line(&mut self, timeout: Option<Duration>) -> io::Result<Input> {
+        if !self.pending {
+            if self.requests.send(()).is_err() {
+                return Ok(Input::Closed);
+            }
+            self.pending = true;
+        }
+        let received = match timeout {
+            Some(timeout) => self.lines.recv_timeout(timeout),
+            None => self.lines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(line) => {
+                self.pending = false;
+                Ok(Input::Line(line?))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(Input::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => Ok(Input::Closed),
+        }
+    }
+}
+
+/// The saved shell history of one key. Lines starting with a space are not
+/// recorded, nor is a line repeating the one before it.
+pub struct History {
+    path: Option<PathBuf>,
+    mode: HistoryMode,
+    max_entries: usize,
+    entries: Vec<String>,
+    unsaved: bool,
+}
+
+impl History {
+    /// Loads the history of `key_name`. An encrypted history needs the
+    /// key's `encryption_key`; one that cannot be read is started afresh.
+    pub fn load(key_name: &str, config: &HistoryConfig, encryption_key: Option<&EncryptionKey>) -> History {
+        let path = match config.mode {
+            HistoryMode::Off => None,
+            HistoryMode::Plain => home_dir().map(|home| home.join(".commune").join("history").join(key_name)),
+            HistoryMode::Encrypted => {
+                home_dir().map(|home| home.join(".commune").join("history").join(format!("{}.enc", key_name)))
+            }
+        };
+        let mut history = History {
+            path,
+            mode: config.mode,
+            max_entries: config.max_entries,
+            entries: Vec::new(),
+            unsaved: false,
+        };
+        if let Err(e) = history.read(encryption_key) {
+            println!("Warning: could not read the shell history, starting a new one: {}", e);
+        }
+        history
+    }
+
+    fn read(&mut self, encryption_key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        let Some(path) = self.path.as_ref().filter(|path| path.exists()) else { return Ok(()) };
+        let text = match (self.mode, encryption_key) {
+            (HistoryMode::Encrypted, Some(encryption_key)) => {
+                let data = encryption::read_encrypted_file(path, encryption_key)?;
+                String::from_utf8_lossy(data.expose()).into_owned()
+            }
+            (HistoryMode::Encrypted, None) => return Ok(()),
+            _ => fs::read_to_string(path)?,
+        };
+        self.entries = text.lines().filter(|line| !line.is_empty()).map(String::from).collect();
+        let excess = self.entries.len().saturating_sub(self.max_entries);
+        self.entries.drain(..excess);
+        Ok(())
+    }
+
+    /// Records `line` and saves the history. An encrypted history is only
+    /// written while `encryption_key` is available; until then new lines are
+    /// kept in memory.
+    pub fn add(&mut self, line: &str, encryption_key: Option<&EncryptionKey>) {
+        if !(line.trim().is_empty() || line.starts_with(' ') || self.entries.last().is_some_and(|last| last == line)) {
+            self.entries.push(line.to_string());
+            let excess = self.entries.len().saturating_sub(self.max_entries);
+            self.entries.drain(..excess);
+            self.unsaved = true;
+        }
+        if self.unsaved {
+            if let Err(e) = self.save(encryption_key) {
+                println!("Warning: could not save the shell history: {}", e);
+            }
+        }
+    }
+
+    fn save(&mut self, encryption_key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        if let Some(parent) = path.parent() {
+            permissions::create_private_dir(parent)?;
+        }
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        match (self.mode, encryption_key) {
+            (HistoryMode::Encrypted, Some(encryption_key)) => {
+                encryption::write_encrypted_file(path, text.as_bytes(), encryption_key)?
+            }
+            (HistoryMode::Encrypted, None) => return Ok(()),
+            _ => write_atomic(path, text.as_bytes())?,
+        }
+        self.unsaved = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use crate::config::StagingMode;
+use crate::cryptography::encryption::{EncryptionError, EncryptionKey};
+use crate::cryptography::staging::StagedKey;
+use crate::shell_words;
+use crate::wrapper;
+
+// A key unlocked for a series of `comx` commands. Unlocking stages the key
+// once and every command runs against that copy. Changes `comx` makes are
+// written back after each command; the encrypted file is not touched when
+// nothing changed. Locking removes the staged key and drops the derived key.
+// A session can be given an idle timeout and a maximum lifetime, after which
+// the caller is expected to lock it.
+
+struct Unlocked {
+    staged: StagedKey,
+    encryption_key: EncryptionKey,
+    unlocked_at: Instant,
+    last_used: Instant,
+}
+
+pub struct Session {
+    key_name: String,
+    staging: StagingMode,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    unlocked: Option<Unlocked>,
+}
+
+impl Session {
+    /// A locked session for `key_name`.
+    pub fn new(key_name: &str, staging: StagingMode) -> Session {
+        Session {
+            key_name: key_name.to_string(),
+            staging,
+            idle_timeout: None,
+            max_lifetime: None,
+            unlocked: None,
+        }
+    }
+
+    /// Limits how long the key may stay unlocked without use, and in total
+    /// after each unlock. `None` means no limit.
+    pub fn set_limits(&mut self, idle_timeout: Option<Duration>, max_lifetime: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+        self.max_lifetime = max_lifetime;
+    }
+
+    /// Time until the session should be locked, if it is unlocked and
+    /// limited. Zero once a limit has passed.
+    pub fn time_left(&self) -> Option<Duration> {
+        let unlocked = self.unlocked.as_ref()?;
+        let idle = self.idle_timeout.map(|timeout| unlocked.last_used + timeout);
+        let lifetime = self.max_lifetime.map(|lifetime| unlocked.unlocked_at + lifetime);
+        let deadline = idle.into_iter().chain(lifetime).min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Counts as activity for the idle timeout.
+    pub fn touch(&mut self) {
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked.last_used = Instant::now();
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked.is_some()
+    }
+
+    /// The derived key while the session is unlocked.
+    pub fn encryption_key(&self) -> Option<&EncryptionKey> {
+        self.unlocked.as_ref().map(|unlocked| &unlocked.encryption_key)
+    }
+
+    /// Stages the key with `encryption_key` for the commands that follow.
+    pub fn unlock(&mut self, encryption_key: EncryptionKey) -> Result<(), EncryptionError> {
+        self.lock()?;
+        let staged = StagedKey::stage(&self.key_name, &encryption_key, self.staging)?;
+        println!("Key '{}' unlocked", self.key_name);
+        let now = Instant::now();
+        self.unlocked = Some(Unlocked { staged, encryption_key, unlocked_at: now, last_used: now });
+        Ok(())
+    }
+
+    /// Runs `comx` with `args` against the staged key and writes back any
+    /// change it made to the key.
+    pub fn run(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let unlocked = self
+            .unlocked
+            .as_mut()
+            .ok_or_else(|| anyhow!("Key '{}' is locked", self.key_name))?;
+        println!("Executing command: comx {}", shell_words::join(args));
+        let result = wrapper::execute_cli_command(&self.key_name, args, unlocked.staged.home());
+        // Even a failed command may have rewritten the key.
+        if unlocked.staged.sync(&unlocked.encryption_key)? {
+            println!("Key changed by comx, encrypted file updated");
+        }
+        result
+    }
+
+    /// Writes back pending changes, then removes the staged key and forgets
+    /// the derived key. If writing back fails the session stays unlocked.
+    pub fn lock(&mut self) -> Result<(), EncryptionError> {
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked.staged.sync(&unlocked.encryption_key)?;
+            self.unlocked = None;
+            println!("Key '{}' locked", self.key_name);
+        }
+        Ok(())
+    }
+
+    /// Locks the session when a limit has passed or it is dropped. Unlike `lock`, the staged
+    /// and derived keys are removed even if writing back changes fails.
+    pub fn expire(&mut self) {
+        if let Err(e) = self.lock() {
+            println!("Failed to write back changes to key '{}': {}", self.key_name, e);
+            self.unlocked = None;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.expire();
+    }
+}
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+// Splits shell input into words the way a POSIX shell does, without running
+// one: single quotes keep everything literally, double quotes keep
+// everything but `\$`, `\``, `\"`, `\\` and backslash-newline, an unquoted
+// backslash escapes the next character and an unquoted `#` at the start of a
+// word begins a comment. Nothing is expanded; `$`, `*` and `~` stay as typed.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// A quote that is never closed, with the column it opens at.
+    UnmatchedQuote { quote: char, column: usize },
+    /// A backslash at the very end of the input.
+    TrailingBackslash { column: usize },
+}
+
+impl ParseError {
+    /// Zero-based column, in characters, the error points at.
+    pub fn column(&self) -> usize {
+        match *self {
+            ParseError::UnmatchedQuote { column, .. } | ParseError::TrailingBackslash { column } => column,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedQuote { quote, column } => {
+                let kind = if *quote == '\'' { "single" } else { "double" };
+                write!(f, "Unmatched {} quote opened at column {}", kind, column + 1)
+            }
+            ParseError::TrailingBackslash { column } => {
+                write!(f, "Backslash at column {} has nothing to escape", column + 1)
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Whether `c` separates words. Like a POSIX shell, only space, tab and
+/// newline do; other Unicode whitespace such as U+00A0 is part of a word.
+pub fn is_separator(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n')
+}
+
+/// Splits `line` into words.
+pub fn split(line: &str) -> Result<Vec<String>, ParseError> {
+    let column = |index: usize| line[..index].chars().count();
+    let mut chars = line.char_indices().peekable();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            c if is_separator(c) => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '#' if !in_word => break,
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some((_, '\n')) => {}
+                    Some((_, escaped)) => word.push(escaped),
+                    None => return Err(ParseError::TrailingBackslash { column: column(index) }),
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => return Err(ParseError::UnmatchedQuote { quote: '\'', column: column(index) }),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                if !double_quoted(&mut chars, &mut word) {
+                    return Err(ParseError::UnmatchedQuote { quote: '"', column: column(index) });
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Appends the rest of a double-quoted string to `word`. Returns false if
+/// the closing quote is missing.
+fn double_quoted(chars: &mut Peekable<CharIndices<'_>>, word: &mut String) -> bool {
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '"' => return true,
+            '\\' => match chars.peek() {
+                Some(&(_, escaped @ ('$' | '`' | '"' | '\\'))) => {
+                    word.push(escaped);
+                    chars.next();
+                }
+                Some(&(_, '\n')) => {
+                    chars.next();
+                }
+                _ => word.push('\\'),
+            },
+            c => word.push(c),
+        }
+    }
+    false
+}
+
+/// Joins `words` into a line that `split` turns back into the same words,
+/// quoting the ones that need it.
+pub fn join(words: &[String]) -> String {
+    let quote = |word: &String| {
+        let plain = !word.is_empty()
+            && word.chars().all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+        if plain {
+            word.clone()
+        } else {
+            format!("'{}'", word.replace('\'', r"'\''"))
+        }
+    };
+    words.iter().map(quote).collect::<Vec<_>>().join(" ")
+}
@@ -1,21 +1,29 @@
 import subprocess
 import getpass
 import argparse
+import os
 import sys
 
 def run_command(command, args):
     password = getpass.getpass("Enter password: ")
     
-    full_command = [command] + args
+    # Hand the password over on a pipe rather than stdin or the command line,
+    # where it would end up in the process list or be read by comx.
+    read_fd, write_fd = os.pipe()
+    os.write(write_fd, (password + '\n').encode())
+    os.close(write_fd)
+
+    full_command = [command, '--password-fd', str(read_fd)] + args
     
     try:
         process = subprocess.Popen(full_command, 
-                                   stdin=subprocess.PIPE, 
                                    stdout=subprocess.PIPE, 
                                    stderr=subprocess.PIPE, 
+                                   pass_fds=(read_fd,),
                                    text=True)
+        os.close(read_fd)
         
-        stdout, stderr = process.communicate(input=password + '\n')
+        stdout, stderr = process.communicate()
         
         if process.returncode != 0:
             print(f"Error: {stderr}")
@@ -1,9 +1,22 @@
+use std::path::Path;
 use std::process::{Command, Stdio};
+use crate::cryptography::cleanup;
+use crate::cryptography::secret::SecretString;
 
-pub fn execute_cli_command(_key_name: &str, args: &[String]) -> anyhow::Result<()> {
-    let output = Command::new("comx")
-        .args(args)
-        .output()?;
+/// Runs `comx` with `args`. `home` overrides HOME, for keys staged outside
+/// the real `~/.commune`.
+pub fn execute_cli_command(_key_name: &str, args: &[String], home: Option<&Path>) -> anyhow::Result<()> {
+    let mut command = Command::new("comx");
+    command.args(args);
+    if let Some(home) = home {
+        command.env("HOME", home);
+    }
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let output = cleanup::wait_for_child(child)?;
 
     if !output.status.success() {
         eprintln!(
@@ -22,11 +35,15 @@ pub fn execute_cli_command(_key_name: &str, args: &[String]) -> anyhow::Result<(
     Ok(())
 }
 
-pub fn regen_key_command(key_name: &str, mnemonic: &str) -> anyhow::Result<()> {
-    let mut child = Command::new("comx")
-        .arg("key")
-        .arg("regen")
-        .arg(key_name)
+/// Runs `comx key regen`, feeding it `mnemonic`. `home` overrides HOME as
+/// for `execute_cli_command`.
+pub fn regen_key_command(key_name: &str, mnemonic: &SecretString, home: Option<&Path>) -> anyhow::Result<()> {
+    let mut command = Command::new("comx");
+    command.arg("key").arg("regen").arg(key_name);
+    if let Some(home) = home {
+        command.env("HOME", home);
+    }
+    let mut child = command
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -36,11 +53,11 @@ pub fn regen_key_command(key_name: &str, mnemonic: &str) -> anyhow::Result<()> {
         // Write the mnemonic to the command's stdin
         use std::io::Write;
         if let Some(stdin) = &mut child.stdin {
-            stdin.write_all(mnemonic.as_bytes())?;
+            stdin.write_all(mnemonic.expose().as_bytes())?;
         }
     }
 
-    let output = child.wait_with_output()?;
+    let output = cleanup::wait_for_child(child)?;
 
     if !output.status.success() {
         eprintln!(
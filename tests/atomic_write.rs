@@ -0,0 +1,70 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use cli_wrapper::cryptography::atomic::{self, AtomicFile};
+
+fn leftover_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn write_replaces_existing_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key.enc");
+    fs::write(&path, b"old ciphertext").unwrap();
+
+    atomic::write_atomic(&path, b"new ciphertext").unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), b"new ciphertext");
+    assert_eq!(leftover_files(dir.path()), vec!["key.enc"]);
+}
+
+#[test]
+fn failed_write_leaves_original_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key.enc");
+    fs::write(&path, b"old ciphertext").unwrap();
+
+    // Simulates running out of disk space halfway through the new contents.
+    let result = atomic::write_atomic_with(&path, |file| {
+        file.write_all(b"new ciph")?;
+        Err(io::Error::new(io::ErrorKind::StorageFull, "simulated full disk"))
+    });
+
+    assert!(result.is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"old ciphertext");
+    assert_eq!(leftover_files(dir.path()), vec!["key.enc"]);
+}
+
+#[test]
+fn crash_before_commit_leaves_original_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key.enc");
+    fs::write(&path, b"old ciphertext").unwrap();
+
+    // A process killed mid-write never runs destructors, so the temporary
+    // file stays behind but the target is never truncated.
+    let mut staged = AtomicFile::create(&path).unwrap();
+    staged.write_all(b"new ciph").unwrap();
+    std::mem::forget(staged);
+
+    assert_eq!(fs::read(&path).unwrap(), b"old ciphertext");
+    let leftovers = leftover_files(dir.path());
+    assert_eq!(leftovers.len(), 2);
+    assert!(leftovers.iter().any(|name| name.starts_with(".key.enc.tmp-")));
+}
+
+#[test]
+fn write_creates_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key.json");
+
+    atomic::write_atomic(&path, b"{}").unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), b"{}");
+}
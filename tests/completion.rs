@@ -0,0 +1,34 @@
+use cli_wrapper::repl::ShellHelper;
+use rustyline::completion::Completer;
+use rustyline::history::DefaultHistory;
+use rustyline::Context;
+
+fn complete(line: &str) -> (usize, Vec<String>) {
+    let helper = ShellHelper::new(vec!["alice".to_string(), "bob".to_string()]);
+    let history = DefaultHistory::new();
+    let (start, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+    (start, pairs.into_iter().map(|pair| pair.replacement).collect())
+}
+
+#[test]
+fn completes_shell_commands() {
+    assert_eq!(complete("lo"), (0, vec!["lock ".to_string()]));
+}
+
+#[test]
+fn completes_comx_subcommands() {
+    assert_eq!(complete("comx misc c"), (10, vec!["circulating-supply ".to_string()]));
+}
+
+#[test]
+fn completes_key_names() {
+    assert_eq!(complete("comx balance show a"), (18, vec!["alice ".to_string()]));
+}
+
+#[test]
+fn multi_byte_whitespace_is_part_of_a_word() {
+    // U+00A0 does not separate words, as in `shell_words::split`.
+    assert_eq!(complete("comx\u{a0}ke"), (0, vec![]));
+    let line = "comx ke\u{a0}";
+    assert_eq!(complete(line), (5, vec![]));
+}
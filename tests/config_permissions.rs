@@ -0,0 +1,44 @@
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use cli_wrapper::cryptography::permissions::check_config;
+
+fn write_config(dir: &Path, mode: u32) -> std::path::PathBuf {
+    let path = dir.join("cli_wrapper.toml");
+    fs::write(&path, "[pinentry]\nprogram = \"pinentry\"\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    path
+}
+
+#[test]
+fn private_config_is_accepted() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+    assert!(check_config(&write_config(dir.path(), 0o600)).is_ok());
+    assert!(check_config(&write_config(dir.path(), 0o644)).is_ok());
+}
+
+#[test]
+fn config_writable_by_others_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+    assert!(check_config(&write_config(dir.path(), 0o666)).is_err());
+}
+
+#[test]
+fn directory_writable_by_others_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(dir.path(), 0o600);
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+    assert!(check_config(&path).is_err());
+}
+
+#[test]
+fn symlinked_config_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+    let target = write_config(dir.path(), 0o600);
+    let link = dir.path().join("link.toml");
+    symlink(&target, &link).unwrap();
+    assert!(check_config(&link).is_err());
+}
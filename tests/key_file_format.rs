@@ -0,0 +1,125 @@
+use cli_wrapper::cryptography::derive::Kdf;
+use cli_wrapper::cryptography::encryption::EncryptionError;
+use cli_wrapper::cryptography::format::{self, Cipher, Header, KeyFile, MAGIC, NONCE_LEN};
+
+fn argon2_header() -> Header {
+    let kdf = Kdf::Argon2id { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 };
+    Header::new(kdf, &[7u8; 16], [3u8; NONCE_LEN], [9u8; 16])
+}
+
+fn format_error(data: &[u8]) -> String {
+    match format::parse(data) {
+        Err(EncryptionError::FormatError(message)) => message,
+        Err(e) => panic!("expected a format error, got {:?}", e),
+        Ok(_) => panic!("expected a format error, got a parsed file"),
+    }
+}
+
+#[test]
+fn header_round_trips() {
+    let header = argon2_header();
+    let mut data = header.to_bytes();
+    data.extend_from_slice(b"ciphertext");
+
+    let KeyFile::Versioned { header: parsed, header_bytes, ciphertext } = format::parse(&data).unwrap() else {
+        panic!("expected a versioned file");
+    };
+    assert_eq!(parsed, header);
+    assert_eq!(parsed.cipher, Cipher::Aes256Gcm);
+    assert_eq!(header_bytes, header.to_bytes().as_slice());
+    assert_eq!(ciphertext, b"ciphertext");
+}
+
+#[test]
+fn pbkdf2_header_round_trips() {
+    let header = Header::new(Kdf::Pbkdf2Sha256 { iterations: 100_000 }, &[1u8; 16], [2u8; NONCE_LEN], [4u8; 16]);
+    let data = header.to_bytes();
+
+    let KeyFile::Versioned { header: parsed, ciphertext, .. } = format::parse(&data).unwrap() else {
+        panic!("expected a versioned file");
+    };
+    assert_eq!(parsed, header);
+    assert!(ciphertext.is_empty());
+}
+
+#[test]
+fn headerless_file_is_read_as_version_0() {
+    let mut data = vec![5u8; NONCE_LEN];
+    data.extend_from_slice(b"ciphertext");
+
+    let KeyFile::Legacy { nonce, ciphertext } = format::parse(&data).unwrap() else {
+        panic!("expected a headerless file");
+    };
+    assert_eq!(nonce, &[5u8; NONCE_LEN]);
+    assert_eq!(ciphertext, b"ciphertext");
+}
+
+#[test]
+fn headerless_file_shorter_than_nonce_is_rejected() {
+    assert!(format::parse(&[5u8; NONCE_LEN - 1]).is_err());
+}
+
+#[test]
+fn version_1_has_no_key_check() {
+    let mut header = argon2_header();
+    header.version = 1;
+    header.key_check = None;
+    let mut data = header.to_bytes();
+    data.extend_from_slice(b"ciphertext");
+
+    let KeyFile::Versioned { header: parsed, ciphertext, .. } = format::parse(&data).unwrap() else {
+        panic!("expected a versioned file");
+    };
+    assert_eq!(parsed.version, 1);
+    assert_eq!(parsed.key_check, None);
+    assert_eq!(ciphertext, b"ciphertext");
+}
+
+#[test]
+fn truncated_header_is_rejected() {
+    let data = argon2_header().to_bytes();
+    // Every prefix that still starts with the magic but stops before the end
+    // of the header.
+    for len in MAGIC.len()..data.len() {
+        assert_eq!(format_error(&data[..len]), "Truncated header", "prefix of {} bytes", len);
+    }
+}
+
+#[test]
+fn unknown_version_is_rejected() {
+    let mut data = argon2_header().to_bytes();
+    data[MAGIC.len()] = 3;
+    assert_eq!(format_error(&data), "Unsupported key file version 3");
+    data[MAGIC.len()] = 0;
+    assert_eq!(format_error(&data), "Unsupported key file version 0");
+}
+
+#[test]
+fn unknown_kdf_is_rejected() {
+    let mut data = argon2_header().to_bytes();
+    data[MAGIC.len() + 1] = 9;
+    assert_eq!(format_error(&data), "Unknown KDF id 9");
+}
+
+#[test]
+fn invalid_kdf_parameters_are_rejected() {
+    let header = Header::new(Kdf::Pbkdf2Sha256 { iterations: 0 }, &[1u8; 16], [2u8; NONCE_LEN], [4u8; 16]);
+    assert_eq!(format_error(&header.to_bytes()), "Invalid PBKDF2 iteration count");
+}
+
+#[test]
+fn unknown_cipher_is_rejected() {
+    let mut data = argon2_header().to_bytes();
+    // magic, version, kdf id, three u32 parameters, salt length and salt.
+    let cipher_offset = MAGIC.len() + 1 + 1 + 12 + 1 + 16;
+    data[cipher_offset] = 7;
+    assert_eq!(format_error(&data), "Unknown cipher id 7");
+}
+
+#[test]
+fn wrong_nonce_length_is_rejected() {
+    let mut data = argon2_header().to_bytes();
+    let nonce_len_offset = MAGIC.len() + 1 + 1 + 12 + 1 + 16 + 1;
+    data[nonce_len_offset] = 16;
+    assert_eq!(format_error(&data), "Invalid nonce length 16");
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use cli_wrapper::cryptography::cleanup::KeyFileGuard;
+use cli_wrapper::cryptography::derive::{self, Kdf};
+use cli_wrapper::cryptography::encryption::{self, EncryptionError, EncryptionKey, KeyFileState};
+use cli_wrapper::cryptography::format::{Header, NONCE_LEN};
+use cli_wrapper::cryptography::secret::SecretKey;
+
+// The vault lives under $HOME, which is shared by every test in this binary.
+static HOME_LOCK: Mutex<()> = Mutex::new(());
+
+struct Vault {
+    _lock: MutexGuard<'static, ()>,
+    home: tempfile::TempDir,
+}
+
+impl Vault {
+    fn new() -> Vault {
+        let lock = HOME_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let home = tempfile::tempdir().unwrap();
+        std::env::set_var("HOME", home.path());
+        Vault { _lock: lock, home }
+    }
+
+    fn key_dir(&self) -> PathBuf {
+        self.home.path().join(".commune").join("key")
+    }
+
+    fn encrypted_path(&self, key_name: &str) -> PathBuf {
+        self.key_dir().join("encrypted").join(format!("{}.enc", key_name))
+    }
+
+    fn write_encrypted(&self, key_name: &str, data: &[u8]) {
+        let path = self.encrypted_path(key_name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+}
+
+// Keys are built directly rather than derived from a password, which would
+// make every test pay for Argon2id.
+fn test_key(byte: u8, kdf: Kdf) -> EncryptionKey {
+    let mut key = SecretKey::zeroed();
+    key.expose_mut().fill(byte);
+    EncryptionKey { key, kdf, salt: vec![7u8; 16] }
+}
+
+fn argon2_key(byte: u8) -> EncryptionKey {
+    test_key(byte, Kdf::Argon2id { memory_kib: 8192, iterations: 1, parallelism: 1 })
+}
+
+fn seal(key: &EncryptionKey, nonce: &[u8; NONCE_LEN], data: &[u8], aad: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key.key.expose()));
+    cipher.encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad }).unwrap()
+}
+
+// A headerless `nonce || ciphertext` file as written before the header existed.
+fn version_0_file(key: &EncryptionKey, data: &[u8]) -> Vec<u8> {
+    let nonce = [5u8; NONCE_LEN];
+    let mut file = nonce.to_vec();
+    file.extend(seal(key, &nonce, data, &[]));
+    file
+}
+
+fn version_1_file(key: &EncryptionKey, data: &[u8]) -> Vec<u8> {
+    let mut header = Header::new(key.kdf, &key.salt, [6u8; NONCE_LEN], [0u8; 16]);
+    header.version = 1;
+    header.key_check = None;
+    let mut file = header.to_bytes();
+    file.extend(seal(key, &header.nonce, data, &header.to_bytes()));
+    file
+}
+
+fn flip_last_byte(path: &Path) {
+    let mut data = fs::read(path).unwrap();
+    *data.last_mut().unwrap() ^= 0x01;
+    fs::write(path, data).unwrap();
+}
+
+#[test]
+fn version_2_round_trips() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    encryption::write_encrypted_key("alice", b"{\"a\":1}", &key).unwrap();
+
+    let data = fs::read(vault.encrypted_path("alice")).unwrap();
+    assert_eq!(data[4], 2);
+    let decrypted = encryption::read_encrypted_key("alice", &key).unwrap();
+    assert_eq!(decrypted.expose(), b"{\"a\":1}");
+}
+
+#[test]
+fn version_1_and_0_files_decrypt() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    vault.write_encrypted("one", &version_1_file(&key, b"version one"));
+    let legacy_key = test_key(2, derive::LEGACY_KDF);
+    vault.write_encrypted("zero", &version_0_file(&legacy_key, b"version zero"));
+
+    assert_eq!(encryption::read_encrypted_key("one", &key).unwrap().expose(), b"version one");
+    assert_eq!(encryption::read_encrypted_key("zero", &legacy_key).unwrap().expose(), b"version zero");
+}
+
+#[test]
+fn wrong_password_is_detected_by_the_key_check() {
+    let _vault = Vault::new();
+    encryption::write_encrypted_key("alice", b"secret", &argon2_key(1)).unwrap();
+
+    let result = encryption::read_encrypted_key("alice", &argon2_key(2));
+    assert!(matches!(result, Err(EncryptionError::WrongPassword)), "{:?}", result.err());
+}
+
+#[test]
+fn flipped_ciphertext_byte_is_reported_as_corruption() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    encryption::write_encrypted_key("alice", b"secret", &key).unwrap();
+    flip_last_byte(&vault.encrypted_path("alice"));
+
+    let result = encryption::read_encrypted_key("alice", &key);
+    assert!(matches!(result, Err(EncryptionError::CorruptedFile(_))), "{:?}", result.err());
+}
+
+#[test]
+fn files_without_key_check_cannot_tell_wrong_password_from_corruption() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    vault.write_encrypted("one", &version_1_file(&key, b"secret"));
+    let result = encryption::read_encrypted_key("one", &argon2_key(2));
+    assert!(matches!(result, Err(EncryptionError::AuthenticationFailed)), "{:?}", result.err());
+
+    let legacy_key = test_key(2, derive::LEGACY_KDF);
+    vault.write_encrypted("zero", &version_0_file(&legacy_key, b"secret"));
+    flip_last_byte(&vault.encrypted_path("zero"));
+    let result = encryption::read_encrypted_key("zero", &legacy_key);
+    assert!(matches!(result, Err(EncryptionError::AuthenticationFailed)), "{:?}", result.err());
+}
+
+#[test]
+fn round_trip_check_rejects_bad_files() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    encryption::write_encrypted_key("alice", b"secret", &key).unwrap();
+    let path = vault.encrypted_path("alice");
+
+    encryption::verify_round_trip(&path, b"secret", &key).unwrap();
+    let result = encryption::verify_round_trip(&path, b"other", &key);
+    assert!(matches!(result, Err(EncryptionError::VerificationFailed(_))), "{:?}", result.err());
+    let result = encryption::verify_round_trip(&path, b"secret", &argon2_key(2));
+    assert!(matches!(result, Err(EncryptionError::VerificationFailed(_))), "{:?}", result.err());
+    flip_last_byte(&path);
+    let result = encryption::verify_round_trip(&path, b"secret", &key);
+    assert!(matches!(result, Err(EncryptionError::VerificationFailed(_))), "{:?}", result.err());
+}
+
+#[test]
+fn key_file_state_follows_the_files_on_disk() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    let plaintext = vault.key_dir().join("alice.json");
+    assert_eq!(encryption::key_file_state("alice", &key).unwrap(), KeyFileState::Missing);
+
+    fs::create_dir_all(vault.key_dir()).unwrap();
+    fs::write(&plaintext, b"secret").unwrap();
+    assert_eq!(encryption::key_file_state("alice", &key).unwrap(), KeyFileState::OnlyPlaintext);
+
+    encryption::write_encrypted_key("alice", b"secret", &key).unwrap();
+    assert_eq!(encryption::key_file_state("alice", &key).unwrap(), KeyFileState::Matching);
+
+    fs::write(&plaintext, b"changed by comx").unwrap();
+    assert_eq!(encryption::key_file_state("alice", &key).unwrap(), KeyFileState::Diverged);
+    assert!(matches!(
+        encryption::decrypt_key_file("alice", &key),
+        Err(EncryptionError::Diverged(_))
+    ));
+
+    fs::remove_file(&plaintext).unwrap();
+    assert_eq!(encryption::key_file_state("alice", &key).unwrap(), KeyFileState::OnlyEncrypted);
+    encryption::decrypt_key_file("alice", &key).unwrap();
+    assert_eq!(fs::read(&plaintext).unwrap(), b"secret");
+}
+
+#[test]
+fn guard_removes_only_the_plaintext_it_wrote() {
+    let vault = Vault::new();
+    let key = argon2_key(1);
+    let plaintext = vault.key_dir().join("alice.json");
+    encryption::write_encrypted_key("alice", b"secret", &key).unwrap();
+
+    drop(KeyFileGuard::stage("alice", &key).unwrap());
+    assert!(!plaintext.exists());
+
+    // Unlocked by the user before the command ran.
+    encryption::decrypt_key_file("alice", &key).unwrap();
+    drop(KeyFileGuard::stage("alice", &key).unwrap());
+    assert_eq!(fs::read(&plaintext).unwrap(), b"secret");
+}
+
+// Leaves `alice` replaced by the new ciphertext and `bob` not yet replaced,
+// as a crash halfway through `passwd` would.
+fn interrupted_password_change(vault: &Vault) {
+    vault.write_encrypted("alice", b"alice new");
+    fs::write(vault.key_dir().join("encrypted/alice.enc.bak"), b"alice old").unwrap();
+    vault.write_encrypted("bob", b"bob old");
+}
+
+#[test]
+fn interrupted_password_change_is_rolled_back_without_the_marker() {
+    let vault = Vault::new();
+    interrupted_password_change(&vault);
+
+    encryption::recover_password_change().unwrap();
+
+    assert_eq!(fs::read(vault.encrypted_path("alice")).unwrap(), b"alice old");
+    assert_eq!(fs::read(vault.encrypted_path("bob")).unwrap(), b"bob old");
+    assert!(!vault.key_dir().join("encrypted/alice.enc.bak").exists());
+}
+
+#[test]
+fn committed_password_change_keeps_the_new_files() {
+    let vault = Vault::new();
+    interrupted_password_change(&vault);
+    let marker = vault.key_dir().join("encrypted/.passwd-committed");
+    fs::write(&marker, b"").unwrap();
+
+    encryption::recover_password_change().unwrap();
+
+    assert_eq!(fs::read(vault.encrypted_path("alice")).unwrap(), b"alice new");
+    assert!(!vault.key_dir().join("encrypted/alice.enc.bak").exists());
+    assert!(!marker.exists());
+}
@@ -0,0 +1,42 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use cli_wrapper::cryptography::cleanup::secure_remove;
+
+#[test]
+fn removes_regular_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key.json");
+    fs::write(&path, b"secret").unwrap();
+
+    secure_remove(&path).unwrap();
+
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn symlink_target_is_left_intact() {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.json");
+    let path = dir.path().join("key.json");
+    fs::write(&backup, b"secret").unwrap();
+    symlink(&backup, &path).unwrap();
+
+    secure_remove(&path).unwrap();
+
+    assert!(fs::symlink_metadata(&path).is_err());
+    assert_eq!(fs::read(&backup).unwrap(), b"secret");
+}
+
+#[test]
+fn hard_linked_copy_is_left_intact() {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.json");
+    let path = dir.path().join("key.json");
+    fs::write(&backup, b"secret").unwrap();
+    fs::hard_link(&backup, &path).unwrap();
+
+    secure_remove(&path).unwrap();
+
+    assert!(!path.exists());
+    assert_eq!(fs::read(&backup).unwrap(), b"secret");
+}
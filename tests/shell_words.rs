@@ -0,0 +1,72 @@
+use cli_wrapper::shell_words::{join, split, ParseError};
+
+fn words(line: &str) -> Vec<String> {
+    split(line).unwrap()
+}
+
+#[test]
+fn splits_on_whitespace() {
+    assert_eq!(words("  comx balance\tshow  alice "), vec!["comx", "balance", "show", "alice"]);
+    assert!(words("   ").is_empty());
+}
+
+#[test]
+fn only_space_tab_and_newline_separate_words() {
+    assert_eq!(words("comx\u{a0}balance\u{3000}show"), vec!["comx\u{a0}balance\u{3000}show"]);
+    assert_eq!(words("comx\nbalance"), vec!["comx", "balance"]);
+}
+
+#[test]
+fn single_quotes_keep_everything_literally() {
+    assert_eq!(words(r#"comx 'a "memo" with \ spaces'"#), vec!["comx", r#"a "memo" with \ spaces"#]);
+    assert_eq!(words("''"), vec![""]);
+}
+
+#[test]
+fn double_quotes_only_unescape_special_characters() {
+    assert_eq!(words(r#""{\"amount\": 1}""#), vec![r#"{"amount": 1}"#]);
+    assert_eq!(words(r#""a\nb \$HOME \\""#), vec![r"a\nb $HOME \"]);
+}
+
+#[test]
+fn backslash_escapes_outside_quotes() {
+    assert_eq!(words(r"memo\ with\ spaces \'x"), vec!["memo with spaces", "'x"]);
+}
+
+#[test]
+fn quoted_and_unquoted_parts_join_into_one_word() {
+    assert_eq!(words(r#"--memo="hello world"'!'"#), vec!["--memo=hello world!"]);
+}
+
+#[test]
+fn comments_start_only_at_word_boundaries() {
+    assert_eq!(words("comx key list # all of them"), vec!["comx", "key", "list"]);
+    assert_eq!(words("comx a#b '#c'"), vec!["comx", "a#b", "#c"]);
+}
+
+#[test]
+fn nothing_is_expanded() {
+    assert_eq!(words("$HOME ~ *.json"), vec!["$HOME", "~", "*.json"]);
+}
+
+#[test]
+fn reports_unbalanced_quotes_and_trailing_backslash() {
+    assert_eq!(split("comx 'abc"), Err(ParseError::UnmatchedQuote { quote: '\'', column: 5 }));
+    assert_eq!(split(r#"é "a\""#), Err(ParseError::UnmatchedQuote { quote: '"', column: 2 }));
+    assert_eq!(split(r"comx \"), Err(ParseError::TrailingBackslash { column: 5 }));
+    assert_eq!(
+        split("comx 'abc").unwrap_err().to_string(),
+        "Unmatched single quote opened at column 6"
+    );
+}
+
+#[test]
+fn join_quotes_words_so_they_split_back() {
+    let original: Vec<String> = ["transfer", "my memo", "", "it's", r#"{"a": 1}"#, "--json"]
+        .iter()
+        .map(|word| word.to_string())
+        .collect();
+    let line = join(&original);
+    assert_eq!(line, r#"transfer 'my memo' '' 'it'\''s' '{"a": 1}' --json"#);
+    assert_eq!(split(&line).unwrap(), original);
+}
@@ -0,0 +1,54 @@
+use cli_wrapper::cryptography::strength::estimate;
+
+fn score(password: &str) -> u8 {
+    estimate(password).score
+}
+
+#[test]
+fn repeated_character_is_weak() {
+    assert!(score("aaaaaaaaaaaaaaaaaaaa") < 2);
+    assert!(score("abcabcabcabcabcabc") < 3);
+}
+
+#[test]
+fn sequences_are_weak() {
+    assert!(score("abcdefghijklmnopqrstuvwxyz") < 2);
+    assert!(score("987654321098") < 3);
+    assert!(score("qwertyuiopasdfghjkl") < 3);
+}
+
+#[test]
+fn word_with_year_and_symbol_is_weak() {
+    assert!(score("Summer2024!") < 3);
+    assert!(score("Liverpool1987") < 3);
+}
+
+#[test]
+fn leetspeak_does_not_hide_a_common_password() {
+    assert!(score("P@ssw0rd2024!") < 3);
+    assert!(score("$ecr3t!") < 3);
+}
+
+#[test]
+fn common_password_scores_zero() {
+    let estimate = estimate("Password123");
+    assert_eq!(estimate.score, 0);
+    assert!(estimate.feedback.is_some());
+}
+
+#[test]
+fn passphrase_is_strong() {
+    assert!(score("correct horse battery staple 42!") >= 3);
+    assert!(score("plum tractor ivory sundial") >= 3);
+}
+
+#[test]
+fn random_password_is_strong() {
+    assert!(score("x7#Kq!9vLp2@Rt") >= 3);
+}
+
+#[test]
+fn long_password_is_estimated_quickly() {
+    let password = "a".repeat(10_000);
+    assert!(score(&password) < 3);
+}